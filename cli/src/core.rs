@@ -1,4 +1,5 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use common::{keys::Scope, upload::Visibility};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -27,10 +28,15 @@ pub enum Commands {
         #[arg(long, env = "FLAN_ADMIN_KEY")]
         admin_key: String,
     },
-    /// Upload an image
+    /// Upload one or more images
     Upload {
-        /// Path to the image file
-        file: PathBuf,
+        /// Paths or glob patterns of the image files
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Who the images are served to
+        #[arg(long, value_enum, default_value_t = ImageVisibility::Unlisted)]
+        visibility: ImageVisibility,
 
         /// Username for authentication
         #[arg(short, long, env = "FLAN_USERNAME")]
@@ -50,6 +56,11 @@ pub enum Commands {
         #[arg(long, env = "FLAN_ACCESS_KEY")]
         access_key: String,
     },
+    /// List a user's public images
+    Gallery {
+        /// User whose images to list
+        username: String,
+    },
     /// Get an image
     Get {
         /// File ID of the image
@@ -58,6 +69,86 @@ pub enum Commands {
         /// Optional output path (defaults to current directory with file ID as name)
         #[arg(short, long)]
         output: Option<PathBuf>,
+
+        /// Named preset configured on the server
+        #[arg(long)]
+        preset: Option<String>,
+    },
+    /// Create a signed, expiring URL for an image, e.g. a private one
+    GetUrl {
+        /// File ID of the image
+        file_id: String,
+
+        /// How long the URL stays valid, in seconds (defaults to the server's setting)
+        #[arg(long)]
+        expires_in: Option<u64>,
+
+        /// Width to resize the image to
+        #[arg(long)]
+        width: Option<u32>,
+
+        /// Height to resize the image to
+        #[arg(long)]
+        height: Option<u32>,
+
+        /// Output quality
+        #[arg(long)]
+        quality: Option<u8>,
+
+        /// Output format
+        #[arg(long)]
+        format: Option<String>,
+
+        /// How the image fits the width and height (contain, cover, fill or pad)
+        #[arg(long)]
+        fit: Option<String>,
+
+        /// Part of the image kept when cropping (center, top, bottom, left, right or smart)
+        #[arg(long)]
+        gravity: Option<String>,
+
+        /// Background color of padded images, as rrggbb or rrggbbaa hex
+        #[arg(long)]
+        background: Option<String>,
+
+        /// Named preset configured on the server
+        #[arg(long)]
+        preset: Option<String>,
+
+        /// Any other transform parameter, e.g. `rotate=90` or `blur=2` (repeatable)
+        #[arg(long = "param", value_name = "NAME=VALUE", value_parser = parse_param)]
+        params: Vec<(String, String)>,
+
+        /// Username for authentication
+        #[arg(long, env = "FLAN_USERNAME")]
+        username: String,
+
+        /// Access key for authentication
+        #[arg(long, env = "FLAN_ACCESS_KEY")]
+        access_key: String,
+    },
+    /// Show storage usage and quota
+    Usage {
+        /// Username for authentication
+        #[arg(long, env = "FLAN_USERNAME")]
+        username: String,
+
+        /// Access key for authentication
+        #[arg(long, env = "FLAN_ACCESS_KEY")]
+        access_key: String,
+    },
+    /// Manage API keys
+    Keys {
+        #[command(subcommand)]
+        command: KeyCommands,
+
+        /// Username for authentication
+        #[arg(long, env = "FLAN_USERNAME", global = true)]
+        username: Option<String>,
+
+        /// Access key for authentication
+        #[arg(long, env = "FLAN_ACCESS_KEY", global = true)]
+        access_key: Option<String>,
     },
     /// Delete an image
     Delete {
@@ -73,3 +164,74 @@ pub enum Commands {
         access_key: String,
     },
 }
+
+#[derive(Subcommand)]
+pub enum KeyCommands {
+    /// Create a new API key
+    Create {
+        /// Name to recognize the key by
+        name: String,
+
+        /// What the key may do, repeatable. Defaults to everything.
+        #[arg(long = "scope", value_enum)]
+        scopes: Vec<KeyScope>,
+
+        /// Make the key stop working after this many days
+        #[arg(long)]
+        expires_in_days: Option<u32>,
+    },
+    /// List API keys
+    List,
+    /// Revoke an API key
+    Revoke {
+        /// ID of the key to revoke
+        key_id: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum KeyScope {
+    /// List images and view usage
+    Read,
+    /// Upload images
+    Upload,
+    /// Delete images
+    Delete,
+}
+
+impl From<KeyScope> for Scope {
+    fn from(scope: KeyScope) -> Scope {
+        match scope {
+            KeyScope::Read => Scope::Read,
+            KeyScope::Upload => Scope::Upload,
+            KeyScope::Delete => Scope::Delete,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ImageVisibility {
+    /// Served to anyone
+    Public,
+    /// Served to anyone who knows the file ID
+    Unlisted,
+    /// Only served through signed URLs
+    Private,
+}
+
+impl From<ImageVisibility> for Visibility {
+    fn from(visibility: ImageVisibility) -> Visibility {
+        match visibility {
+            ImageVisibility::Public => Visibility::Public,
+            ImageVisibility::Unlisted => Visibility::Unlisted,
+            ImageVisibility::Private => Visibility::Private,
+        }
+    }
+}
+
+fn parse_param(param: &str) -> Result<(String, String), String> {
+    param
+        .split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got `{}`", param))
+}
@@ -1,24 +1,38 @@
+use chrono::{Duration, Utc};
 use clap::Parser;
-use color_eyre::eyre::{eyre, Result};
+use color_eyre::eyre::{eyre, Report, Result};
 use comfy_table::{
     modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, Attribute, Cell, Color, ContentArrangement,
     Table,
 };
 use common::{
+    error::ErrorResponse,
+    keys::{CreateApiKeyRequest, CreateApiKeyResponse, ListApiKeysResponse, Scope},
     list::ListImagesResponse,
     register::{RegisterUserRequest, RegisterUserResponse},
-    upload::UploadImageResponse,
+    signed_url::{SignedUrlRequest, SignedUrlResponse},
+    upload::{UploadBatchResponse, UploadOptions, UploadOutcome},
+    usage::UsageResponse,
 };
 use console::style;
-use core::{Cli, Commands};
+use core::{Cli, Commands, KeyCommands};
 use reqwest::{
-    header::{HeaderMap, HeaderValue},
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE},
     multipart::{Form, Part},
-    Client, StatusCode,
+    Client, Response, StatusCode,
 };
 use std::path::PathBuf;
 mod core;
 
+/// Turns a 401 or 403 into an error carrying the server's explanation.
+async fn auth_error(response: Response) -> Report {
+    let message = response
+        .json::<ErrorResponse>()
+        .await
+        .map_or_else(|_| "Invalid credentials".to_string(), |body| body.error);
+    eyre!("{} {}", style("✘").red().bold(), message)
+}
+
 async fn register_user(
     client: &Client,
     server_url: &str,
@@ -52,28 +66,54 @@ async fn register_user(
     }
 }
 
-async fn upload_image(
+/// Expands glob patterns, passing through arguments that are plain paths.
+fn expand_paths(patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let matches = glob::glob(pattern)?
+            .filter_map(|entry| entry.ok())
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+
+        if matches.is_empty() {
+            paths.push(PathBuf::from(pattern));
+        } else {
+            paths.extend(matches);
+        }
+    }
+
+    Ok(paths)
+}
+
+async fn upload_images(
     client: &Client,
     server_url: &str,
-    file_path: PathBuf,
+    patterns: Vec<String>,
+    options: UploadOptions,
     username: String,
     access_key: String,
 ) -> Result<()> {
-    let file_name = &file_path
-        .file_name()
-        .ok_or_else(|| eyre!("Invalid file name"))?
-        .to_string_lossy();
-
-    // Read file
-    let file = tokio::fs::read(file_name.to_string()).await?;
-
-    // Create multipart form
-    let form = Form::new().part(
-        "file",
-        Part::bytes(file)
-            .file_name(file_name.to_string())
-            .mime_str("application/octet-stream")?,
-    );
+    // Create multipart form with one part per file
+    let mut form = Form::new();
+    for file_path in expand_paths(&patterns)? {
+        let file_name = file_path
+            .file_name()
+            .ok_or_else(|| eyre!("Invalid file name: {}", file_path.display()))?
+            .to_string_lossy()
+            .to_string();
+
+        let file = tokio::fs::read(&file_path)
+            .await
+            .map_err(|e| eyre!("Failed to read {}: {}", file_path.display(), e))?;
+        let mime = mime_guess::from_path(&file_path).first_or_octet_stream();
+
+        form = form.part(
+            "file",
+            Part::bytes(file)
+                .file_name(file_name)
+                .mime_str(mime.as_ref())?,
+        );
+    }
 
     // Prepare headers
     let mut headers = HeaderMap::new();
@@ -83,22 +123,111 @@ async fn upload_image(
     let response = client
         .post(format!("{}/api/upload", server_url))
         .headers(headers)
+        .query(&options)
         .multipart(form)
         .send()
         .await?;
 
+    // If no file was stored, the batch comes with the status of the first failure
+    let is_batch = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+
     match response.status() {
-        StatusCode::OK => {
-            let upload_response: UploadImageResponse = response.json().await?;
-            println!("Image uploaded successfully:");
-            println!("File ID: {}", upload_response.file_id);
-            println!("URL: {}", upload_response.url);
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(auth_error(response).await),
+        status if status == StatusCode::OK || is_batch => {
+            let upload_response: UploadBatchResponse = response.json().await?;
+            let mut failed = 0;
+            let mut too_large = false;
+            for result in upload_response.files {
+                let file_name = result.file_name.unwrap_or_default();
+                match result.outcome {
+                    UploadOutcome::Uploaded(upload) => {
+                        println!(
+                            "{} {} {} {}",
+                            style("✔").green().bold(),
+                            file_name,
+                            style(&upload.file_id).cyan(),
+                            style(&upload.url).dim()
+                        );
+                        for variant in &upload.variants {
+                            println!("  {} {}", variant.preset, style(&variant.url).dim());
+                        }
+                    }
+                    UploadOutcome::Failed { code, error } => {
+                        failed += 1;
+                        too_large |= code == StatusCode::PAYLOAD_TOO_LARGE.as_u16();
+                        println!("{} {} {}", style("✘").red().bold(), file_name, error);
+                    }
+                }
+            }
+
+            if too_large {
+                println!(
+                    "{}",
+                    style("Check your size limits and storage quota with `flan-cli usage`").dim()
+                );
+            }
+            if failed > 0 {
+                return Err(eyre!(
+                    "{} {} file(s) failed to upload",
+                    style("✘").red().bold(),
+                    failed
+                ));
+            }
             Ok(())
         }
-        StatusCode::UNAUTHORIZED => Err(eyre!("{} Invalid credentials", style("✘").red().bold())),
         StatusCode::BAD_REQUEST => {
             Err(eyre!("{} Invalid file or request", style("✘").red().bold()))
         }
+        StatusCode::PAYLOAD_TOO_LARGE => Err(eyre!(
+            "{} Request is too large or over your storage quota",
+            style("✘").red().bold()
+        )),
+        _ => Err(eyre!(
+            "{} Server error: {} - {}",
+            style("✘").red().bold(),
+            response.status(),
+            response.text().await?
+        )),
+    }
+}
+
+async fn get_signed_url(
+    client: &Client,
+    server_url: &str,
+    file_id: &str,
+    request: SignedUrlRequest,
+    username: &str,
+    access_key: &str,
+) -> Result<()> {
+    // Prepare headers
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Username", HeaderValue::from_str(username)?);
+    headers.insert("X-Access-Key", HeaderValue::from_str(access_key)?);
+
+    let response = client
+        .post(format!("{}/api/images/{}/url", server_url, file_id))
+        .headers(headers)
+        .json(&request)
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => {
+            let signed: SignedUrlResponse = response.json().await?;
+            println!("{}{}", server_url, signed.url);
+            println!(
+                "{} {}",
+                style("Expires:").bold(),
+                style(signed.expires_at).dim()
+            );
+            Ok(())
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(auth_error(response).await),
+        StatusCode::NOT_FOUND => Err(eyre!("{} Image not found", style("✘").red().bold())),
+        StatusCode::BAD_REQUEST => Err(eyre!("{} Invalid parameters", style("✘").red().bold())),
         _ => Err(eyre!(
             "{} Server error: {} - {}",
             style("✘").red().bold(),
@@ -128,35 +257,36 @@ async fn list_images(
     match response.status() {
         reqwest::StatusCode::OK => {
             let result: ListImagesResponse = response.json().await?;
+            print_images(&result);
+            Ok(())
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(auth_error(response).await),
+        _ => Err(eyre!(
+            "{} Server error: {} - {}",
+            style("✘").red().bold(),
+            response.status(),
+            response.text().await?
+        )),
+    }
+}
 
-            if result.images.is_empty() {
-                println!("No images found.");
-            } else {
-                let mut table = Table::new();
-                table
-                    .set_content_arrangement(ContentArrangement::Dynamic)
-                    .load_preset(UTF8_FULL)
-                    .apply_modifier(UTF8_ROUND_CORNERS)
-                    .set_header(vec![
-                        Cell::new("File ID")
-                            .add_attribute(Attribute::Bold)
-                            .fg(Color::Green),
-                        Cell::new("Created At")
-                            .add_attribute(Attribute::Bold)
-                            .fg(Color::Cyan),
-                    ]);
-                for image in &result.images {
-                    table.add_row(vec![
-                        Cell::new(&image.file_id),
-                        Cell::new(image.created_at.to_string()),
-                    ]);
-                }
+async fn list_public_images(client: &Client, server_url: &str, username: &str) -> Result<()> {
+    let response = client
+        .get(format!("{}/api/users/{}/images", server_url, username))
+        .send()
+        .await?;
 
-                println!("{table}");
-            }
+    match response.status() {
+        StatusCode::OK => {
+            let result: ListImagesResponse = response.json().await?;
+            print_images(&result);
             Ok(())
         }
-        StatusCode::UNAUTHORIZED => Err(eyre!("{} Invalid credentials", style("✘").red().bold())),
+        StatusCode::NOT_FOUND => Err(eyre!(
+            "{} No user named {}",
+            style("✘").red().bold(),
+            username
+        )),
         _ => Err(eyre!(
             "{} Server error: {} - {}",
             style("✘").red().bold(),
@@ -166,14 +296,44 @@ async fn list_images(
     }
 }
 
+fn print_images(result: &ListImagesResponse) {
+    if result.images.is_empty() {
+        println!("No images found.");
+        return;
+    }
+
+    let mut table = Table::new();
+    table
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_header(vec![
+            Cell::new("File ID")
+                .add_attribute(Attribute::Bold)
+                .fg(Color::Green),
+            Cell::new("Created At")
+                .add_attribute(Attribute::Bold)
+                .fg(Color::Cyan),
+        ]);
+    for image in &result.images {
+        table.add_row(vec![
+            Cell::new(&image.file_id),
+            Cell::new(image.created_at.to_string()),
+        ]);
+    }
+
+    println!("{table}");
+}
+
 async fn get_image(
     client: &Client,
     server: &str,
     file_id: String,
     output: Option<PathBuf>,
+    preset: Option<String>,
 ) -> Result<()> {
     let url = format!("{}/images/{}", server, file_id);
-    let response = client.get(&url).send().await?;
+    let response = client.get(&url).query(&[("preset", preset)]).send().await?;
 
     match response.status() {
         StatusCode::OK => {
@@ -207,6 +367,253 @@ async fn get_image(
             Ok(())
         }
         StatusCode::NOT_FOUND => Err(eyre!("{} Image not found", style("✘").red().bold())),
+        StatusCode::BAD_REQUEST => Err(eyre!("{} Unknown preset", style("✘").red().bold())),
+        _ => Err(eyre!(
+            "{} Server error: {} - {}",
+            style("✘").red().bold(),
+            response.status(),
+            response.text().await?
+        )),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+async fn show_usage(
+    client: &Client,
+    server_url: &str,
+    username: &str,
+    access_key: &str,
+) -> Result<()> {
+    // Prepare headers
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Username", HeaderValue::from_str(username)?);
+    headers.insert("X-Access-Key", HeaderValue::from_str(access_key)?);
+
+    let response = client
+        .get(format!("{}/api/usage", server_url))
+        .headers(headers)
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => {
+            let usage: UsageResponse = response.json().await?;
+
+            let storage_limit = usage
+                .max_bytes
+                .map_or_else(|| "unlimited".to_string(), format_bytes);
+            let image_limit = usage
+                .max_images
+                .map_or_else(|| "unlimited".to_string(), |max| max.to_string());
+
+            println!("{}", style("Storage Usage").bold());
+            println!("{}", style("─────────────").dim());
+            println!(
+                "{} {} / {}",
+                style("Storage:").bold(),
+                style(format_bytes(usage.bytes_used)).cyan(),
+                storage_limit
+            );
+            println!(
+                "{} {} / {}",
+                style("Images:").bold(),
+                style(usage.image_count).cyan(),
+                image_limit
+            );
+            println!(
+                "{} {}",
+                style("Max file size:").bold(),
+                format_bytes(usage.max_file_size)
+            );
+            Ok(())
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(auth_error(response).await),
+        _ => Err(eyre!(
+            "{} Server error: {} - {}",
+            style("✘").red().bold(),
+            response.status(),
+            response.text().await?
+        )),
+    }
+}
+
+fn format_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(Scope::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+async fn create_key(
+    client: &Client,
+    server_url: &str,
+    request: CreateApiKeyRequest,
+    username: &str,
+    access_key: &str,
+) -> Result<()> {
+    // Prepare headers
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Username", HeaderValue::from_str(username)?);
+    headers.insert("X-Access-Key", HeaderValue::from_str(access_key)?);
+
+    let response = client
+        .post(format!("{}/api/keys", server_url))
+        .headers(headers)
+        .json(&request)
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => {
+            let created: CreateApiKeyResponse = response.json().await?;
+            println!("{} API key created", style("✔").green().bold());
+            println!("{} {}", style("ID:").bold(), created.id);
+            println!("{} {}", style("Name:").bold(), created.name);
+            println!(
+                "{} {}",
+                style("Scopes:").bold(),
+                format_scopes(&created.scopes)
+            );
+            if let Some(expires_at) = created.expires_at {
+                println!("{} {}", style("Expires:").bold(), expires_at);
+            }
+            println!("{} {}", style("Key:").bold(), style(&created.key).cyan());
+            println!(
+                "{}",
+                style("Store this key now, it won't be shown again.").dim()
+            );
+            Ok(())
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(auth_error(response).await),
+        StatusCode::BAD_REQUEST => Err(eyre!("{} Invalid key name", style("✘").red().bold())),
+        _ => Err(eyre!(
+            "{} Server error: {} - {}",
+            style("✘").red().bold(),
+            response.status(),
+            response.text().await?
+        )),
+    }
+}
+
+async fn list_keys(
+    client: &Client,
+    server_url: &str,
+    username: &str,
+    access_key: &str,
+) -> Result<()> {
+    // Prepare headers
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Username", HeaderValue::from_str(username)?);
+    headers.insert("X-Access-Key", HeaderValue::from_str(access_key)?);
+
+    let response = client
+        .get(format!("{}/api/keys", server_url))
+        .headers(headers)
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => {
+            let result: ListApiKeysResponse = response.json().await?;
+
+            let mut table = Table::new();
+            table
+                .set_content_arrangement(ContentArrangement::Dynamic)
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec![
+                    Cell::new("ID")
+                        .add_attribute(Attribute::Bold)
+                        .fg(Color::Green),
+                    Cell::new("Name").add_attribute(Attribute::Bold),
+                    Cell::new("Prefix").add_attribute(Attribute::Bold),
+                    Cell::new("Scopes").add_attribute(Attribute::Bold),
+                    Cell::new("Created At")
+                        .add_attribute(Attribute::Bold)
+                        .fg(Color::Cyan),
+                    Cell::new("Last Used")
+                        .add_attribute(Attribute::Bold)
+                        .fg(Color::Cyan),
+                    Cell::new("Expires")
+                        .add_attribute(Attribute::Bold)
+                        .fg(Color::Cyan),
+                ]);
+            for key in &result.keys {
+                table.add_row(vec![
+                    Cell::new(&key.id),
+                    Cell::new(&key.name),
+                    Cell::new(&key.prefix),
+                    Cell::new(format_scopes(&key.scopes)),
+                    Cell::new(key.created_at.to_string()),
+                    Cell::new(
+                        key.last_used_at
+                            .map_or_else(|| "never".to_string(), |at| at.to_string()),
+                    ),
+                    Cell::new(
+                        key.expires_at
+                            .map_or_else(|| "never".to_string(), |at| at.to_string()),
+                    ),
+                ]);
+            }
+
+            println!("{table}");
+            Ok(())
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(auth_error(response).await),
+        _ => Err(eyre!(
+            "{} Server error: {} - {}",
+            style("✘").red().bold(),
+            response.status(),
+            response.text().await?
+        )),
+    }
+}
+
+async fn revoke_key(
+    client: &Client,
+    server_url: &str,
+    key_id: &str,
+    username: &str,
+    access_key: &str,
+) -> Result<()> {
+    // Prepare headers
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Username", HeaderValue::from_str(username)?);
+    headers.insert("X-Access-Key", HeaderValue::from_str(access_key)?);
+
+    let response = client
+        .delete(format!("{}/api/keys/{}", server_url, key_id))
+        .headers(headers)
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::NO_CONTENT => {
+            println!("{} API key revoked: {}", style("✔").green().bold(), key_id);
+            Ok(())
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(auth_error(response).await),
+        StatusCode::NOT_FOUND => Err(eyre!("{} Key not found", style("✘").red().bold())),
+        StatusCode::CONFLICT => Err(eyre!(
+            "{} Can't revoke your last key with every scope that never expires, create another one first",
+            style("✘").red().bold()
+        )),
         _ => Err(eyre!(
             "{} Server error: {} - {}",
             style("✘").red().bold(),
@@ -239,6 +646,8 @@ async fn delete_image(
             println!("Image deleted successfully: {}", file_id);
             Ok(())
         }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(auth_error(response).await),
+        StatusCode::NOT_FOUND => Err(eyre!("{} Image not found", style("✘").red().bold())),
         _ => Err(eyre!(
             "{} Server error: {} - {}",
             style("✘").red().bold(),
@@ -262,14 +671,61 @@ async fn main() -> Result<()> {
             register_user(&client, &cli.server, username, admin_key).await?;
         }
         Commands::Upload {
-            file,
+            files,
+            visibility,
             username,
             access_key,
         } => {
-            upload_image(&client, &cli.server, file, username, access_key).await?;
+            let options = UploadOptions {
+                visibility: visibility.into(),
+            };
+            upload_images(&client, &cli.server, files, options, username, access_key).await?;
         }
-        Commands::Get { file_id, output } => {
-            get_image(&client, &cli.server, file_id, output).await?;
+        Commands::GetUrl {
+            file_id,
+            expires_in,
+            width,
+            height,
+            quality,
+            format,
+            fit,
+            gravity,
+            background,
+            preset,
+            params,
+            username,
+            access_key,
+        } => {
+            let named = [
+                ("width", width.map(|v| v.to_string())),
+                ("height", height.map(|v| v.to_string())),
+                ("quality", quality.map(|v| v.to_string())),
+                ("format", format),
+                ("fit", fit),
+                ("gravity", gravity),
+                ("background", background),
+                ("preset", preset),
+            ]
+            .into_iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value?)));
+            let params = named.chain(params).collect();
+            let request = SignedUrlRequest { expires_in, params };
+            get_signed_url(
+                &client,
+                &cli.server,
+                &file_id,
+                request,
+                &username,
+                &access_key,
+            )
+            .await?;
+        }
+        Commands::Get {
+            file_id,
+            output,
+            preset,
+        } => {
+            get_image(&client, &cli.server, file_id, output, preset).await?;
         }
         Commands::List {
             username,
@@ -277,6 +733,46 @@ async fn main() -> Result<()> {
         } => {
             list_images(&client, &cli.server, &username, &access_key).await?;
         }
+        Commands::Gallery { username } => {
+            list_public_images(&client, &cli.server, &username).await?;
+        }
+        Commands::Usage {
+            username,
+            access_key,
+        } => {
+            show_usage(&client, &cli.server, &username, &access_key).await?;
+        }
+        Commands::Keys {
+            command,
+            username,
+            access_key,
+        } => {
+            let username = username.ok_or_else(|| eyre!("--username is required"))?;
+            let access_key = access_key.ok_or_else(|| eyre!("--access-key is required"))?;
+
+            match command {
+                KeyCommands::Create {
+                    name,
+                    scopes,
+                    expires_in_days,
+                } => {
+                    let request = CreateApiKeyRequest {
+                        name,
+                        scopes: (!scopes.is_empty())
+                            .then(|| scopes.into_iter().map(Scope::from).collect()),
+                        expires_at: expires_in_days
+                            .map(|days| Utc::now() + Duration::days(days.into())),
+                    };
+                    create_key(&client, &cli.server, request, &username, &access_key).await?;
+                }
+                KeyCommands::List => {
+                    list_keys(&client, &cli.server, &username, &access_key).await?;
+                }
+                KeyCommands::Revoke { key_id } => {
+                    revoke_key(&client, &cli.server, &key_id, &username, &access_key).await?;
+                }
+            }
+        }
         Commands::Delete {
             file_id,
             username,
@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimingStats {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransformStats {
+    pub max_concurrent: usize,
+    pub running: usize,
+    pub max_queued: usize,
+    pub queued: usize,
+    /// Requests turned away with 503 because the queue was full.
+    pub rejected: u64,
+    /// Time spent waiting for a free slot.
+    pub queue_wait: TimingStats,
+    /// Time spent decoding, transforming and encoding.
+    pub transform_time: TimingStats,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheStats {
+    /// Entries in Redis, and their total size in bytes.
+    pub entries: u64,
+    pub bytes: u64,
+    /// Requests served from Redis since startup.
+    pub hits: u64,
+    /// Requests served from variants kept in storage since startup.
+    pub stored_hits: u64,
+    /// Requests that rendered an image since startup.
+    pub misses: u64,
+    pub hit_ratio: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlushCacheResponse {
+    /// Entries deleted from Redis.
+    pub entries: u64,
+    /// Variants deleted from storage.
+    pub variants: u64,
+}
@@ -1,6 +1,10 @@
+use crate::transform::TransformParams;
 use confique::Config;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::net::IpAddr;
+use std::path::PathBuf;
 
 #[derive(Debug, Config)]
 pub struct AppConfig {
@@ -12,9 +16,13 @@ pub struct AppConfig {
     #[config(default = "127.0.0.1")]
     pub address: IpAddr,
 
-    // Minio config, pointing to a local Minio instance.
+    // Storage config, selecting where original images are kept.
     #[config(nested)]
-    pub minio: MinioConfig,
+    pub storage: StorageConfig,
+
+    /// Replaced by `[storage.s3]`, only read to refuse configs that still use it.
+    #[config(nested)]
+    pub minio: LegacyMinioConfig,
 
     // Admin key for the admin to register accounts.
     #[config(default = "admin-key")]
@@ -29,6 +37,158 @@ pub struct AppConfig {
 
     #[config(nested)]
     pub redis: RedisConfig,
+
+    #[config(nested)]
+    pub limits: LimitsConfig,
+
+    #[config(nested)]
+    pub signing: SigningConfig,
+
+    #[config(nested)]
+    pub transforms: TransformConfig,
+
+    #[config(nested)]
+    pub cache_control: CacheControlConfig,
+
+    #[config(nested)]
+    pub cache: CacheConfig,
+}
+
+#[derive(Debug, Config)]
+pub struct SigningConfig {
+    /// Secret used to sign URLs of private images. If unset, a random one is generated on
+    /// startup and signed URLs stop working on restart.
+    #[config(env = "SIGNING_KEY")]
+    pub key: Option<String>,
+
+    /// How long signed URLs stay valid if the request doesn't say, in seconds.
+    #[config(default = 3600)]
+    pub default_ttl: u64,
+
+    /// Longest validity a signed URL may be requested with, in seconds.
+    #[config(default = 604800)]
+    pub max_ttl: u64,
+}
+
+#[derive(Debug, Config)]
+pub struct TransformConfig {
+    /// Only serve resized or re-encoded images through signed URLs or presets, so anonymous
+    /// callers can't make the server generate arbitrary variants.
+    #[config(env = "REQUIRE_SIGNED_TRANSFORMS", default = false)]
+    pub require_signature: bool,
+
+    /// Most transforms run at once. Defaults to the number of CPUs.
+    #[config(env = "MAX_CONCURRENT_TRANSFORMS")]
+    pub max_concurrent: Option<usize>,
+
+    /// Most transforms waiting for a free slot. Requests past that get a 503.
+    #[config(default = 64)]
+    pub max_queued: usize,
+
+    /// Seconds clients are told to wait in `Retry-After` when the queue is full.
+    #[config(default = 1)]
+    pub retry_after: u64,
+
+    /// Named transforms anyone can request with `?preset=<name>`, even when signatures are
+    /// required.
+    #[config(default = {
+        "thumb": { "width": 200, "height": 200 },
+        "og": { "width": 1200, "height": 630 },
+    })]
+    pub presets: HashMap<String, TransformParams>,
+
+    /// Presets rendered in the background right after an upload, so the first requests for
+    /// them don't wait on a transform.
+    #[config(
+        env = "EAGER_PRESETS",
+        parse_env = confique::env::parse::list_by_sep::<',', _, _>,
+        default = []
+    )]
+    pub eager: Vec<String>,
+
+    /// Most eager variants rendered at once. They take slots from requests, so this keeps
+    /// bursts of uploads from filling the queue.
+    #[config(default = 1)]
+    pub max_eager: usize,
+}
+
+#[derive(Debug, Config)]
+pub struct CacheControlConfig {
+    /// How long browsers and CDNs may keep images whose URL alone determines them, in
+    /// seconds. Images never change once uploaded, so these are also marked immutable.
+    #[config(default = 31536000)]
+    pub immutable_max_age: u64,
+
+    /// How long browsers and CDNs may keep images requested through a preset, in seconds.
+    /// Presets can be changed in the config, so these are revalidated.
+    #[config(default = 3600)]
+    pub max_age: u64,
+}
+
+#[derive(Debug, Config)]
+pub struct CacheConfig {
+    /// How long rendered images stay in Redis, in seconds.
+    #[config(env = "CACHE_REDIS_TTL", default = 3600)]
+    pub redis_ttl: u64,
+
+    /// Also keep rendered variants in storage, under `variants/`, so they outlive their Redis
+    /// entries and are only rendered again once they expire there too.
+    #[config(env = "CACHE_STORE_VARIANTS", default = true)]
+    pub store_variants: bool,
+
+    /// How long variants kept in storage are served, in seconds.
+    #[config(env = "CACHE_STORAGE_TTL", default = 2592000)]
+    pub storage_ttl: u64,
+
+    /// Largest image kept in Redis, in bytes. Larger originals are streamed from storage
+    /// on every request instead of being read into memory.
+    #[config(env = "CACHE_MAX_ENTRY_SIZE", default = 5242880)]
+    pub max_entry_size: u64,
+
+    /// Take a lock in Redis while rendering a variant, so instances sharing the cache don't
+    /// all render it at once. Within one instance, renders are always coalesced.
+    #[config(env = "CACHE_RENDER_LOCK", default = false)]
+    pub render_lock: bool,
+
+    /// How long the render lock is held at most, in seconds. Other instances wait this long
+    /// for the result before rendering the variant themselves.
+    #[config(default = 5)]
+    pub render_lock_timeout: u64,
+}
+
+#[derive(Debug, Config)]
+pub struct LimitsConfig {
+    /// Largest accepted upload, in bytes.
+    #[config(env = "MAX_FILE_SIZE", default = 26214400)]
+    pub max_file_size: u64,
+
+    /// Largest number of files accepted in a single upload request.
+    #[config(env = "MAX_FILES_PER_REQUEST", default = 20)]
+    pub max_files_per_request: usize,
+
+    /// How long an upload request may take, in seconds. Other requests time out after 10.
+    #[config(env = "UPLOAD_TIMEOUT", default = 600)]
+    pub upload_timeout: u64,
+
+    /// Largest accepted image width, in pixels.
+    #[config(default = 16384)]
+    pub max_width: u32,
+
+    /// Largest accepted image height, in pixels.
+    #[config(default = 16384)]
+    pub max_height: u32,
+
+    /// Largest accepted number of pixels (width * height), to reject decompression bombs.
+    #[config(default = 100000000)]
+    pub max_pixels: u64,
+
+    /// Default storage quota per user, in bytes. Unlimited if unset.
+    #[config(env = "QUOTA_BYTES")]
+    pub quota_bytes: Option<u64>,
+
+    /// Default maximum number of images per user. Unlimited if unset.
+    #[config(env = "QUOTA_IMAGES")]
+    pub quota_images: Option<u64>,
 }
 
 #[derive(Debug, Config)]
@@ -40,21 +200,82 @@ pub struct RedisConfig {
     pub pool_size: usize,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// An S3-compatible bucket, e.g. a Minio instance.
+    S3,
+    /// A directory on the local filesystem.
+    Local,
+    /// Process memory. Everything is lost on restart, only useful for tests.
+    Memory,
+}
+
 #[derive(Debug, Config)]
-pub struct MinioConfig {
-    /// Minio endpoint.
+pub struct StorageConfig {
+    /// Storage backend to use: "s3", "local" or "memory".
+    #[config(env = "STORAGE_BACKEND", default = "s3")]
+    pub backend: StorageBackend,
+
+    #[config(nested)]
+    pub s3: S3Config,
+
+    #[config(nested)]
+    pub local: LocalStorageConfig,
+}
+
+#[derive(Debug, Config)]
+pub struct S3Config {
+    /// S3 endpoint.
     #[config(env = "MINIO_ENDPOINT", default = "http://localhost:9000")]
     pub endpoint: String,
 
-    /// Minio access key.
+    /// S3 region.
+    #[config(env = "MINIO_REGION", default = "eu-central-1")]
+    pub region: String,
+
+    /// S3 access key.
     #[config(env = "MINIO_ACCESS_KEY", default = "minioadmin")]
     pub access_key: String,
 
-    /// Minio secret key.
+    /// S3 secret key.
     #[config(env = "MINIO_SECRET_KEY", default = "minioadmin")]
     pub secret_key: String,
 
-    /// Minio bucket name.
+    /// S3 bucket name.
     #[config(env = "MINIO_BUCKET_NAME", default = "images")]
     pub bucket_name: String,
 }
+
+/// The old `[minio]` section, which moved to `[storage.s3]` with the same keys.
+#[derive(Debug, Config)]
+pub struct LegacyMinioConfig {
+    /// Deprecated, use `storage.s3.endpoint`.
+    pub endpoint: Option<String>,
+
+    /// Deprecated, use `storage.s3.access_key`.
+    pub access_key: Option<String>,
+
+    /// Deprecated, use `storage.s3.secret_key`.
+    pub secret_key: Option<String>,
+
+    /// Deprecated, use `storage.s3.bucket_name`.
+    pub bucket_name: Option<String>,
+}
+
+impl LegacyMinioConfig {
+    /// Whether any of the old keys were set.
+    pub fn is_set(&self) -> bool {
+        self.endpoint.is_some()
+            || self.access_key.is_some()
+            || self.secret_key.is_some()
+            || self.bucket_name.is_some()
+    }
+}
+
+#[derive(Debug, Config)]
+pub struct LocalStorageConfig {
+    /// Directory to store images in. Created if it doesn't exist.
+    #[config(env = "STORAGE_LOCAL_PATH", default = "./data/images")]
+    pub path: PathBuf,
+}
@@ -0,0 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+/// Body of error responses that carry a message, e.g. authentication failures.
+#[derive(Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
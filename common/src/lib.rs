@@ -1,5 +1,11 @@
+pub mod admin;
 pub mod config;
 pub use confique::Config;
+pub mod error;
+pub mod keys;
 pub mod list;
 pub mod register;
+pub mod signed_url;
+pub mod transform;
 pub mod upload;
+pub mod usage;
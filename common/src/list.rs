@@ -1,3 +1,4 @@
+use crate::upload::{VariantInfo, Visibility};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
@@ -6,7 +7,10 @@ pub struct ImageInfo {
     pub file_id: String,
 
     pub url: String,
+    pub visibility: Visibility,
     pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub variants: Vec<VariantInfo>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -0,0 +1,19 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct SignedUrlRequest {
+    /// How long the URL stays valid, in seconds. The server's default if unset.
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    /// Transform parameters to bake into the URL, as accepted by `/images/:file_id`.
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignedUrlResponse {
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+
+/// Transform parameters, as given in the query string of an image request or by a preset.
+/// Every one of them is optional, an empty set serves the image as uploaded.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransformParams {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Option<Fit>,
+    pub gravity: Option<Gravity>,
+    /// Background color of padded images, as `rrggbb` or `rrggbbaa` hex.
+    pub background: Option<String>,
+    /// Rectangle cut out of the original image, as `x,y,width,height` in pixels.
+    pub crop: Option<String>,
+    /// Clockwise rotation in degrees, a multiple of 90.
+    pub rotate: Option<i32>,
+    pub flip: Option<Flip>,
+    /// Sigma of a Gaussian blur.
+    pub blur: Option<f32>,
+    /// Sigma of an unsharp mask.
+    pub sharpen: Option<f32>,
+    pub grayscale: Option<bool>,
+    /// Added to every color channel, from -255 to 255.
+    pub brightness: Option<i32>,
+    /// Contrast change in percent, from -100 to 100.
+    pub contrast: Option<f32>,
+    /// Rotate and flip the image as its EXIF orientation says, on unless disabled.
+    pub auto_orient: Option<bool>,
+    pub quality: Option<u8>,
+    /// `jpeg`, `png`, `webp`, `avif`, or `auto` to pick one from the `Accept` header.
+    pub format: Option<String>,
+}
+
+impl TransformParams {
+    /// Fills in the parameters this one doesn't set from `fallback`.
+    pub fn or(self, fallback: &TransformParams) -> TransformParams {
+        let fallback = fallback.clone();
+        TransformParams {
+            width: self.width.or(fallback.width),
+            height: self.height.or(fallback.height),
+            fit: self.fit.or(fallback.fit),
+            gravity: self.gravity.or(fallback.gravity),
+            background: self.background.or(fallback.background),
+            crop: self.crop.or(fallback.crop),
+            rotate: self.rotate.or(fallback.rotate),
+            flip: self.flip.or(fallback.flip),
+            blur: self.blur.or(fallback.blur),
+            sharpen: self.sharpen.or(fallback.sharpen),
+            grayscale: self.grayscale.or(fallback.grayscale),
+            brightness: self.brightness.or(fallback.brightness),
+            contrast: self.contrast.or(fallback.contrast),
+            auto_orient: self.auto_orient.or(fallback.auto_orient),
+            quality: self.quality.or(fallback.quality),
+            format: self.format.or(fallback.format),
+        }
+    }
+}
+
+/// How a resized image is fitted into the requested width and height.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale to fit inside the box, keeping the aspect ratio.
+    #[default]
+    Contain,
+    /// Scale to cover the whole box, keeping the aspect ratio, and crop the overflow.
+    Cover,
+    /// Stretch to exactly the box.
+    Fill,
+    /// Scale to fit inside the box and fill the rest with the background color.
+    Pad,
+}
+
+/// Which part of the image is kept when cropping, or where it's placed when padding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    #[default]
+    Center,
+    Top,
+    Bottom,
+    Left,
+    Right,
+    /// The area with the most detail, measured by entropy. Centered when padding.
+    Smart,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Flip {
+    Horizontal,
+    Vertical,
+    Both,
+}
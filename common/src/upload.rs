@@ -1,7 +1,66 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+/// Who an image is served to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Anyone, and listed among the user's public images.
+    Public,
+    /// Anyone who knows the file ID.
+    #[default]
+    Unlisted,
+    /// Only requests through a signed URL.
+    Private,
+}
+
+/// Query parameters of an upload request.
+#[derive(Default, Serialize, Deserialize)]
+pub struct UploadOptions {
+    #[serde(default)]
+    pub visibility: Visibility,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UploadImageResponse {
     pub file_id: String,
     pub url: String,
+    /// Variants rendered ahead of the first request for them.
+    #[serde(default)]
+    pub variants: Vec<VariantInfo>,
+}
+
+/// A variant of an image, requested through a preset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariantInfo {
+    pub preset: String,
+    pub url: String,
+    /// When the URL stops working, for the signed URLs of private images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Results for every file in an upload request, in the order they were sent.
+#[derive(Serialize, Deserialize)]
+pub struct UploadBatchResponse {
+    pub files: Vec<UploadResult>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UploadResult {
+    /// The file name the client sent, if any.
+    pub file_name: Option<String>,
+    #[serde(flatten)]
+    pub outcome: UploadOutcome,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum UploadOutcome {
+    Uploaded(UploadImageResponse),
+    Failed {
+        /// The HTTP status code this error would have on its own.
+        code: u16,
+        error: String,
+    },
 }
@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct UsageResponse {
+    pub bytes_used: u64,
+    pub image_count: u64,
+    pub max_bytes: Option<u64>,
+    pub max_images: Option<u64>,
+    pub max_file_size: u64,
+}
@@ -0,0 +1,632 @@
+//! API key generation and verification, and the extractor authenticated routes use.
+//!
+//! Keys look like `flan_<prefix>_<secret>`. The prefix is stored in plain text to
+//! look the key up, the whole key only as an Argon2 hash.
+//!
+//! Plain text keys from before API keys are still accepted along with a username. The first
+//! time one is used it's hashed into a key with every scope, under a `legacy_` prefix.
+//!
+//! Since Argon2 is slow on purpose, verified keys are cached in Redis under their prefix
+//! along with a SHA-256 digest of the key, which is cheap to check on later requests.
+
+use crate::db::{self, api_key, user, PrismaClient};
+use crate::state::AppState;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use common::{error::ErrorResponse, keys::Scope};
+use fred::{
+    error::RedisError,
+    prelude::{KeysInterface, RedisPool},
+    types::Expiration,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::{marker::PhantomData, ops::Deref};
+use tracing::{debug, error, info};
+
+const KEY_PREFIX: &str = "flan";
+const PREFIX_LENGTH: usize = 12;
+const SECRET_LENGTH: usize = 36;
+
+/// How long a verified key is trusted from the cache. Also bounds how stale `lastUsedAt` gets.
+const AUTH_CACHE_TTL_SECS: i64 = 300;
+
+/// How stale `lastUsedAt` may get before it's bumped, to avoid a write on every request.
+const LAST_USED_PRECISION: Duration = Duration::minutes(1);
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingCredentials,
+    InvalidCredentials,
+    ExpiredKey,
+    Forbidden,
+    MissingScope(Scope),
+    DatabaseError(String),
+    HashError(String),
+}
+
+impl From<AuthError> for StatusCode {
+    fn from(error: AuthError) -> StatusCode {
+        match error {
+            AuthError::MissingCredentials => StatusCode::UNAUTHORIZED,
+            AuthError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            AuthError::ExpiredKey => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+            AuthError::MissingScope(_) => StatusCode::FORBIDDEN,
+            AuthError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AuthError::HashError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::MissingCredentials => write!(f, "Missing credentials"),
+            AuthError::InvalidCredentials => write!(f, "Invalid credentials"),
+            AuthError::ExpiredKey => write!(f, "API key has expired"),
+            AuthError::Forbidden => write!(f, "Not allowed to access this resource"),
+            AuthError::MissingScope(scope) => write!(f, "API key lacks the {} scope", scope),
+            AuthError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            AuthError::HashError(err) => write!(f, "Hash error: {}", err),
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let message = match &self {
+            AuthError::DatabaseError(_) | AuthError::HashError(_) => {
+                error!("Authentication failed: {}", self);
+                "Internal server error".to_string()
+            }
+            _ => self.to_string(),
+        };
+
+        let challenge = matches!(
+            self,
+            AuthError::MissingCredentials | AuthError::InvalidCredentials | AuthError::ExpiredKey
+        );
+        let mut response = (
+            StatusCode::from(self),
+            Json(ErrorResponse { error: message }),
+        )
+            .into_response();
+        if challenge {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+/// The user a request was made by, resolved from its API key.
+///
+/// Accepts either `Authorization: Bearer <key>` or the `X-Username` and `X-Access-Key` headers.
+/// Rejects the request with a JSON error body if the credentials are missing or invalid.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user_id: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl AuthenticatedUser {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthenticatedUser {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let (username, key) =
+            credentials_from_headers(&parts.headers).ok_or(AuthError::MissingCredentials)?;
+        authenticate(state, username, key).await
+    }
+}
+
+/// Scopes an endpoint requires from the caller's key, see [`Authorized`].
+pub trait RequiredScopes {
+    const SCOPES: &'static [Scope];
+}
+
+/// Listing images and viewing usage.
+pub struct ReadScope;
+
+impl RequiredScopes for ReadScope {
+    const SCOPES: &'static [Scope] = &[Scope::Read];
+}
+
+/// Uploading images.
+pub struct UploadScope;
+
+impl RequiredScopes for UploadScope {
+    const SCOPES: &'static [Scope] = &[Scope::Upload];
+}
+
+/// Deleting images.
+pub struct DeleteScope;
+
+impl RequiredScopes for DeleteScope {
+    const SCOPES: &'static [Scope] = &[Scope::Delete];
+}
+
+/// Managing keys. Requires every scope, so a restricted key can't mint itself a broader one.
+pub struct AllScopes;
+
+impl RequiredScopes for AllScopes {
+    const SCOPES: &'static [Scope] = &Scope::ALL;
+}
+
+/// An [`AuthenticatedUser`] whose key has all the scopes in `S`, rejecting the request with
+/// 403 otherwise.
+pub struct Authorized<S>(AuthenticatedUser, PhantomData<S>);
+
+impl<S> Deref for Authorized<S> {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &AuthenticatedUser {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S: RequiredScopes> FromRequestParts<AppState> for Authorized<S> {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let user = AuthenticatedUser::from_request_parts(parts, state).await?;
+        if let Some(missing) = S::SCOPES.iter().find(|scope| !user.has_scope(**scope)) {
+            return Err(AuthError::MissingScope(*missing));
+        }
+
+        Ok(Authorized(user, PhantomData))
+    }
+}
+
+/// A request made with the admin key in the `X-Admin-Key` header, for operator endpoints.
+pub struct Admin;
+
+#[async_trait]
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let key = parts
+            .headers
+            .get("X-Admin-Key")
+            .and_then(|h| h.to_str().ok())
+            .ok_or(AuthError::MissingCredentials)?;
+        if !constant_time_eq(key, &state.admin_key) {
+            return Err(AuthError::Forbidden);
+        }
+
+        Ok(Admin)
+    }
+}
+
+/// Extracts the optional username and the key from the request headers.
+fn credentials_from_headers(headers: &HeaderMap) -> Option<(Option<&str>, &str)> {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|h: &HeaderValue| h.to_str().ok())
+    };
+
+    if let Some(authorization) = header(header::AUTHORIZATION.as_str()) {
+        let key = authorization.strip_prefix("Bearer ")?.trim();
+        return Some((None, key));
+    }
+
+    Some((Some(header("X-Username")?), header("X-Access-Key")?))
+}
+
+/// A freshly generated key. `key` is only ever shown to the user once.
+struct GeneratedKey {
+    key: String,
+    prefix: String,
+    hash: String,
+}
+
+fn random_string(length: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// Splits a key into its prefix and secret, if it's well-formed.
+fn parse_key(key: &str) -> Option<(&str, &str)> {
+    let rest = key.strip_prefix(KEY_PREFIX)?.strip_prefix('_')?;
+    let (prefix, secret) = rest.split_once('_')?;
+    (prefix.len() == PREFIX_LENGTH && secret.len() == SECRET_LENGTH).then_some((prefix, secret))
+}
+
+/// Prefix a user's legacy key is stored under once hashed. Generated prefixes have no `_`,
+/// so the two can't clash.
+fn legacy_prefix(username: &str) -> String {
+    format!("legacy_{}", username.to_lowercase())
+}
+
+/// Generates a new key and its hash.
+async fn generate_key() -> Result<GeneratedKey, AuthError> {
+    let prefix = random_string(PREFIX_LENGTH);
+    let key = format!("{}_{}_{}", KEY_PREFIX, prefix, random_string(SECRET_LENGTH));
+    let hash = hash_key(&key).await?;
+
+    Ok(GeneratedKey { key, prefix, hash })
+}
+
+/// Hashes a key. Hashing is slow on purpose, so it runs off the async runtime.
+async fn hash_key(key: &str) -> Result<String, AuthError> {
+    let key = key.to_string();
+
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(key.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| AuthError::HashError(e.to_string()))
+    })
+    .await
+    .map_err(|e| AuthError::HashError(e.to_string()))?
+}
+
+/// Checks `key` against a stored hash in constant time.
+async fn verify_key(key: &str, hash: &str) -> Result<bool, AuthError> {
+    let key = key.to_string();
+    let hash = hash.to_string();
+
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash).map_err(|e| AuthError::HashError(e.to_string()))?;
+        Ok(Argon2::default()
+            .verify_password(key.as_bytes(), &hash)
+            .is_ok())
+    })
+    .await
+    .map_err(|e| AuthError::HashError(e.to_string()))?
+}
+
+/// A key verified earlier, as cached in Redis.
+struct CachedKey {
+    digest: String,
+    user_id: String,
+    scopes: Vec<Scope>,
+    expires_at: Option<DateTime<Utc>>,
+    username: String,
+}
+
+impl CachedKey {
+    fn encode(&self) -> String {
+        let scopes = self
+            .scopes
+            .iter()
+            .map(Scope::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        let expires_at = self
+            .expires_at
+            .map(|at| at.timestamp().to_string())
+            .unwrap_or_default();
+
+        // The username goes last as it's the only part that could contain the separator
+        format!(
+            "{}:{}:{}:{}:{}",
+            self.digest, self.user_id, scopes, expires_at, self.username
+        )
+    }
+
+    fn decode(value: &str) -> Option<CachedKey> {
+        let mut parts = value.splitn(5, ':');
+        let digest = parts.next()?.to_string();
+        let user_id = parts.next()?.to_string();
+        let scopes = parts
+            .next()?
+            .split(',')
+            .filter(|scope| !scope.is_empty())
+            .map(|scope| Scope::ALL.into_iter().find(|s| s.to_string() == scope))
+            .collect::<Option<Vec<_>>>()?;
+        let expires_at = match parts.next()? {
+            "" => None,
+            timestamp => Some(DateTime::from_timestamp(timestamp.parse().ok()?, 0)?),
+        };
+
+        Some(CachedKey {
+            digest,
+            user_id,
+            scopes,
+            expires_at,
+            username: parts.next()?.to_string(),
+        })
+    }
+}
+
+fn cache_key(prefix: &str) -> String {
+    format!("auth:{}", prefix)
+}
+
+fn digest(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Compares two strings without bailing out at the first difference.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn get_cached_key(pool: &RedisPool, prefix: &str) -> Result<Option<CachedKey>, RedisError> {
+    let cached = pool.get::<Option<String>, _>(cache_key(prefix)).await?;
+    Ok(cached.as_deref().and_then(CachedKey::decode))
+}
+
+async fn set_cached_key(pool: &RedisPool, prefix: &str, key: &CachedKey) -> Result<(), RedisError> {
+    pool.set(
+        cache_key(prefix),
+        key.encode(),
+        Some(Expiration::EX(AUTH_CACHE_TTL_SECS)),
+        None,
+        false,
+    )
+    .await
+}
+
+/// Drops a key from the cache, so a revoked key stops working right away.
+pub async fn forget_key(pool: &RedisPool, prefix: &str) {
+    if let Err(e) = pool.del::<(), _>(cache_key(prefix)).await {
+        error!("Failed to drop key {} from the cache: {}", prefix, e);
+    }
+}
+
+pub fn scope_from_db(scope: db::Scope) -> Scope {
+    match scope {
+        db::Scope::Read => Scope::Read,
+        db::Scope::Upload => Scope::Upload,
+        db::Scope::Delete => Scope::Delete,
+    }
+}
+
+pub fn scope_to_db(scope: Scope) -> db::Scope {
+    match scope {
+        Scope::Read => db::Scope::Read,
+        Scope::Upload => db::Scope::Upload,
+        Scope::Delete => db::Scope::Delete,
+    }
+}
+
+/// Looks a key up in the database, verifying it against its hash.
+async fn load_key(state: &AppState, prefix: &str, key: &str) -> Result<CachedKey, AuthError> {
+    let api_key = state
+        .db
+        .api_key()
+        .find_unique(api_key::prefix::equals(prefix.to_string()))
+        .exec()
+        .await
+        .map_err(|e| AuthError::DatabaseError(e.to_string()))?
+        .ok_or(AuthError::InvalidCredentials)?;
+
+    if !verify_key(key, &api_key.hash).await? {
+        return Err(AuthError::InvalidCredentials);
+    }
+
+    let owner = state
+        .db
+        .user()
+        .find_unique(user::id::equals(api_key.user_id.clone()))
+        .exec()
+        .await
+        .map_err(|e| AuthError::DatabaseError(e.to_string()))?
+        .ok_or(AuthError::InvalidCredentials)?;
+
+    touch_key(&state.db, &api_key).await;
+
+    Ok(CachedKey {
+        digest: digest(key),
+        user_id: api_key.user_id,
+        scopes: api_key.scopes.into_iter().map(scope_from_db).collect(),
+        expires_at: api_key.expires_at.map(|at| at.to_utc()),
+        username: owner.username,
+    })
+}
+
+/// Hashes a user's legacy key into a key with every scope and clears the plain text one.
+async fn migrate_legacy_key(
+    state: &AppState,
+    username: &str,
+    key: &str,
+) -> Result<CachedKey, AuthError> {
+    let owner = state
+        .db
+        .user()
+        .find_unique(user::username::equals(username.to_string()))
+        .exec()
+        .await
+        .map_err(|e| AuthError::DatabaseError(e.to_string()))?
+        .ok_or(AuthError::InvalidCredentials)?;
+    if !owner
+        .key
+        .as_deref()
+        .is_some_and(|legacy| constant_time_eq(legacy, key))
+    {
+        return Err(AuthError::InvalidCredentials);
+    }
+
+    let hash = hash_key(key).await?;
+
+    // Clearing the plain text key first lets only one of several concurrent requests migrate it
+    let cleared = state
+        .db
+        .user()
+        .update_many(
+            vec![
+                user::id::equals(owner.id.clone()),
+                user::key::equals(Some(key.to_string())),
+            ],
+            vec![user::key::set(None)],
+        )
+        .exec()
+        .await
+        .map_err(|e| AuthError::DatabaseError(e.to_string()))?;
+    if cleared == 0 {
+        return load_key(state, &legacy_prefix(username), key).await;
+    }
+
+    let api_key = state
+        .db
+        .api_key()
+        .create(
+            "legacy".to_string(),
+            legacy_prefix(username),
+            hash,
+            user::id::equals(owner.id.clone()),
+            vec![api_key::scopes::set(
+                Scope::ALL.into_iter().map(scope_to_db).collect(),
+            )],
+        )
+        .exec()
+        .await
+        .map_err(|e| AuthError::DatabaseError(e.to_string()))?;
+    info!("Hashed the legacy key of {}", owner.username);
+
+    Ok(CachedKey {
+        digest: digest(key),
+        user_id: api_key.user_id,
+        scopes: Scope::ALL.to_vec(),
+        expires_at: None,
+        username: owner.username,
+    })
+}
+
+/// Resolves the user an API key belongs to. If a username is given, the key must belong to it.
+pub async fn authenticate(
+    state: &AppState,
+    username: Option<&str>,
+    key: &str,
+) -> Result<AuthenticatedUser, AuthError> {
+    let (prefix, legacy) = match (parse_key(key), username) {
+        (Some((prefix, _)), _) => (prefix.to_string(), None),
+        // Legacy keys were always sent along with the username
+        (None, Some(username)) => (legacy_prefix(username), Some(username)),
+        (None, None) => return Err(AuthError::InvalidCredentials),
+    };
+    let prefix = prefix.as_str();
+
+    let cached = match get_cached_key(&state.redis, prefix).await {
+        Ok(cached) => cached,
+        Err(e) => {
+            error!("Failed to read cached credentials: {}", e);
+            None
+        }
+    };
+
+    let resolved = match cached {
+        Some(cached) => {
+            debug!("Using cached credentials for key {}", prefix);
+            if !constant_time_eq(&cached.digest, &digest(key)) {
+                return Err(AuthError::InvalidCredentials);
+            }
+            cached
+        }
+        None => {
+            let loaded = match (load_key(state, prefix, key).await, legacy) {
+                (Err(AuthError::InvalidCredentials), Some(username)) => {
+                    migrate_legacy_key(state, username, key).await?
+                }
+                (loaded, _) => loaded?,
+            };
+            if let Err(e) = set_cached_key(&state.redis, prefix, &loaded).await {
+                error!("Failed to cache credentials: {}", e);
+            }
+            loaded
+        }
+    };
+
+    if username.is_some_and(|username| !username.eq_ignore_ascii_case(&resolved.username)) {
+        return Err(AuthError::InvalidCredentials);
+    }
+    if resolved.expires_at.is_some_and(|at| at <= Utc::now()) {
+        return Err(AuthError::ExpiredKey);
+    }
+
+    Ok(AuthenticatedUser {
+        user_id: resolved.user_id,
+        scopes: resolved.scopes,
+    })
+}
+
+/// Records that a key was just used, unless that was already recorded recently.
+async fn touch_key(db: &PrismaClient, api_key: &api_key::Data) {
+    let now = Utc::now();
+    let is_stale = api_key
+        .last_used_at
+        .is_none_or(|last_used| now - last_used.to_utc() > LAST_USED_PRECISION);
+    if !is_stale {
+        return;
+    }
+
+    if let Err(e) = db
+        .api_key()
+        .update(
+            api_key::id::equals(api_key.id.clone()),
+            vec![api_key::last_used_at::set(Some(now.into()))],
+        )
+        .exec()
+        .await
+    {
+        error!("Failed to update last use of key {}: {}", api_key.prefix, e);
+    }
+}
+
+/// Creates a named key for the user and returns it, along with the stored row.
+pub async fn create_key(
+    db: &PrismaClient,
+    user_id: &str,
+    name: &str,
+    scopes: &[Scope],
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(String, api_key::Data), AuthError> {
+    let generated = generate_key().await?;
+
+    let api_key = db
+        .api_key()
+        .create(
+            name.to_string(),
+            generated.prefix,
+            generated.hash,
+            user::id::equals(user_id.to_string()),
+            vec![
+                api_key::scopes::set(scopes.iter().copied().map(scope_to_db).collect()),
+                api_key::expires_at::set(expires_at.map(Into::into)),
+            ],
+        )
+        .exec()
+        .await
+        .map_err(|e| AuthError::DatabaseError(e.to_string()))?;
+
+    Ok((generated.key, api_key))
+}
@@ -0,0 +1,342 @@
+//! Processed images cached in Redis. Entries carry the metadata their responses need next to
+//! the bytes, so a cache hit is served without looking the image up.
+//!
+//! Every image also has a set of the keys its entries are cached under, so they can all be
+//! purged when it's deleted.
+
+use bytes::{Buf, BufMut, Bytes};
+use chrono::{DateTime, Utc};
+use common::admin::CacheStats;
+use fred::{
+    error::RedisError,
+    prelude::{KeysInterface, RedisPool, SetsInterface},
+    types::{Expiration, RedisKey, SetOptions},
+};
+use futures_util::TryStreamExt;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::time::{sleep, Instant};
+use tracing::debug;
+
+/// Prefix of the keys entries are cached under.
+pub const KEY_PREFIX: &str = "img:";
+
+/// Prefix of the sets of keys cached for each image.
+const FILE_KEYS_PREFIX: &str = "img-keys:";
+
+/// Keys are deleted in batches of this many.
+const DELETE_BATCH: usize = 500;
+
+/// Bumped whenever the entry layout changes, entries of other versions are cache misses.
+const ENTRY_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedImage {
+    pub content_type: String,
+    pub etag: String,
+    /// Whole seconds, as HTTP dates have no finer resolution.
+    pub last_modified: DateTime<Utc>,
+    pub data: Bytes,
+}
+
+impl CachedImage {
+    /// Lays the entry out as the version, the content type and ETag prefixed by their u16
+    /// lengths, the last-modified Unix timestamp, and the data prefixed by its u64 length,
+    /// all big-endian.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(
+            1 + 2 + self.content_type.len() + 2 + self.etag.len() + 8 + 8 + self.data.len(),
+        );
+        entry.put_u8(ENTRY_VERSION);
+        entry.put_u16(self.content_type.len() as u16);
+        entry.put_slice(self.content_type.as_bytes());
+        entry.put_u16(self.etag.len() as u16);
+        entry.put_slice(self.etag.as_bytes());
+        entry.put_i64(self.last_modified.timestamp());
+        entry.put_u64(self.data.len() as u64);
+        entry.put_slice(&self.data);
+        entry
+    }
+
+    /// Reads an entry written by [`CachedImage::encode`]. Returns `None` for entries of
+    /// another version, or whose length doesn't add up.
+    pub fn decode(mut entry: Bytes) -> Option<Self> {
+        if take(&mut entry, 1)?.get_u8() != ENTRY_VERSION {
+            return None;
+        }
+
+        let len = take(&mut entry, 2)?.get_u16();
+        let content_type = String::from_utf8(take(&mut entry, len.into())?.to_vec()).ok()?;
+        let len = take(&mut entry, 2)?.get_u16();
+        let etag = String::from_utf8(take(&mut entry, len.into())?.to_vec()).ok()?;
+        let last_modified = DateTime::from_timestamp(take(&mut entry, 8)?.get_i64(), 0)?;
+
+        let len = take(&mut entry, 8)?.get_u64();
+        if entry.len() as u64 != len {
+            return None;
+        }
+
+        Some(CachedImage {
+            content_type,
+            etag,
+            last_modified,
+            data: entry,
+        })
+    }
+}
+
+fn take(entry: &mut Bytes, len: usize) -> Option<Bytes> {
+    (entry.len() >= len).then(|| entry.split_to(len))
+}
+
+pub async fn get(pool: &RedisPool, cache_key: &str) -> Result<Option<CachedImage>, RedisError> {
+    debug!("Attempting to get image from cache with key: {}", cache_key);
+    let Some(entry) = pool.get::<Option<Bytes>, _>(cache_key).await? else {
+        return Ok(None);
+    };
+
+    let cached = CachedImage::decode(entry);
+    if cached.is_none() {
+        debug!("Ignoring unreadable cache entry: {}", cache_key);
+    }
+    Ok(cached)
+}
+
+fn file_keys_key(file_id: &str) -> String {
+    format!("{}{}", FILE_KEYS_PREFIX, file_id)
+}
+
+pub async fn set(
+    pool: &RedisPool,
+    file_id: &str,
+    cache_key: &str,
+    image: &CachedImage,
+    ttl_secs: i64,
+) -> Result<(), RedisError> {
+    debug!("Caching image with key: {}", cache_key);
+    pool.set::<(), _, _>(
+        cache_key,
+        image.encode(),
+        Some(Expiration::EX(ttl_secs)),
+        Some(SetOptions::NX),
+        false,
+    )
+    .await?;
+
+    // All entries share a TTL, so the set outlives every entry it lists
+    let file_keys = file_keys_key(file_id);
+    pool.sadd::<(), _, _>(&file_keys, cache_key).await?;
+    pool.expire(&file_keys, ttl_secs).await
+}
+
+/// Deletes every cached entry of an image, returns how many there were.
+pub async fn purge(pool: &RedisPool, file_id: &str) -> Result<u64, RedisError> {
+    debug!("Purging cached entries of {}", file_id);
+    let file_keys = file_keys_key(file_id);
+    let keys: Vec<RedisKey> = pool.smembers(&file_keys).await?;
+    let mut purged = 0;
+    for batch in keys.chunks(DELETE_BATCH) {
+        purged += pool.del::<u64, _>(batch.to_vec()).await?;
+    }
+    pool.del::<(), _>(file_keys).await?;
+    Ok(purged)
+}
+
+async fn scan(pool: &RedisPool, prefix: &str) -> Result<Vec<RedisKey>, RedisError> {
+    pool.next()
+        .scan_buffered(format!("{}*", prefix), Some(DELETE_BATCH as u32), None)
+        .try_collect()
+        .await
+}
+
+/// Deletes every cached entry, returns how many there were.
+pub async fn purge_all(pool: &RedisPool) -> Result<u64, RedisError> {
+    debug!("Purging all cached entries");
+    let mut purged = 0;
+    for batch in scan(pool, KEY_PREFIX).await?.chunks(DELETE_BATCH) {
+        purged += pool.del::<u64, _>(batch.to_vec()).await?;
+    }
+    for batch in scan(pool, FILE_KEYS_PREFIX).await?.chunks(DELETE_BATCH) {
+        pool.del::<(), _>(batch.to_vec()).await?;
+    }
+    Ok(purged)
+}
+
+/// Counts cached entries and their total size in bytes. Walks the whole keyspace, so it's
+/// only meant for the admin API.
+pub async fn size(pool: &RedisPool) -> Result<(u64, u64), RedisError> {
+    let keys = scan(pool, KEY_PREFIX).await?;
+    let mut bytes = 0;
+    for key in &keys {
+        bytes += pool.strlen::<u64, _>(key).await?;
+    }
+    Ok((keys.len() as u64, bytes))
+}
+
+/// How image requests were served since startup, for this instance.
+#[derive(Default)]
+pub struct CacheCounters {
+    hits: AtomicU64,
+    stored_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheCounters {
+    /// Served from Redis.
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Served from a variant kept in storage.
+    pub fn stored_hit(&self) {
+        self.stored_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Rendered from the original.
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self, entries: u64, bytes: u64) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let stored_hits = self.stored_hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let requests = hits + stored_hits + misses;
+        CacheStats {
+            entries,
+            bytes,
+            hits,
+            stored_hits,
+            misses,
+            hit_ratio: match requests {
+                0 => 0.0,
+                _ => (hits + stored_hits) as f64 / requests as f64,
+            },
+        }
+    }
+}
+
+/// How often instances waiting for another one to render an entry check on it.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn lock_key(cache_key: &str) -> String {
+    format!("lock:{}", cache_key)
+}
+
+/// Takes the lock for rendering the entry under `cache_key`, returns whether it was free.
+pub async fn lock(pool: &RedisPool, cache_key: &str, ttl_secs: u64) -> Result<bool, RedisError> {
+    let taken: Option<String> = pool
+        .set(
+            lock_key(cache_key),
+            1,
+            Some(Expiration::EX(ttl_secs as i64)),
+            Some(SetOptions::NX),
+            false,
+        )
+        .await?;
+    Ok(taken.is_some())
+}
+
+/// Releases the render lock. If it had expired and another instance took it since, this
+/// releases theirs, which at worst costs a duplicate render.
+pub async fn unlock(pool: &RedisPool, cache_key: &str) -> Result<(), RedisError> {
+    pool.del(lock_key(cache_key)).await
+}
+
+/// Waits up to `timeout` for the instance holding the render lock to cache the entry. Gives up
+/// early if the lock is released without an entry, e.g. because it was too large to cache.
+pub async fn wait_for(pool: &RedisPool, cache_key: &str, timeout: Duration) -> Option<CachedImage> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        sleep(LOCK_POLL_INTERVAL).await;
+        if let Ok(Some(cached)) = get(pool, cache_key).await {
+            return Some(cached);
+        }
+        if !pool
+            .exists::<bool, _>(lock_key(cache_key))
+            .await
+            .unwrap_or(true)
+        {
+            return None;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> CachedImage {
+        CachedImage {
+            content_type: "image/webp".to_string(),
+            etag: "\"0123456789abcdef\"".to_string(),
+            last_modified: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            data: Bytes::from_static(b"RIFF\0\0\0\0WEBPVP8 "),
+        }
+    }
+
+    #[test]
+    fn hit_ratio_counts_both_tiers() {
+        let counters = CacheCounters::default();
+        assert_eq!(counters.stats(0, 0).hit_ratio, 0.0);
+
+        counters.hit();
+        counters.hit();
+        counters.stored_hit();
+        counters.miss();
+        let stats = counters.stats(3, 300);
+        assert_eq!((stats.hits, stats.stored_hits, stats.misses), (2, 1, 1));
+        assert_eq!(stats.hit_ratio, 0.75);
+    }
+
+    #[test]
+    fn entry_round_trips() {
+        let entry = Bytes::from(image().encode());
+        assert_eq!(CachedImage::decode(entry), Some(image()));
+    }
+
+    #[test]
+    fn empty_data_round_trips() {
+        let image = CachedImage {
+            data: Bytes::new(),
+            ..image()
+        };
+        let entry = Bytes::from(image.encode());
+        assert_eq!(CachedImage::decode(entry), Some(image));
+    }
+
+    #[test]
+    fn truncated_entry_is_a_miss() {
+        let entry = image().encode();
+        for len in 0..entry.len() {
+            assert_eq!(
+                CachedImage::decode(Bytes::copy_from_slice(&entry[..len])),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn entry_with_trailing_bytes_is_a_miss() {
+        let mut entry = image().encode();
+        entry.push(0);
+        assert_eq!(CachedImage::decode(Bytes::from(entry)), None);
+    }
+
+    #[test]
+    fn other_version_is_a_miss() {
+        let mut entry = image().encode();
+        entry[0] = ENTRY_VERSION + 1;
+        assert_eq!(CachedImage::decode(Bytes::from(entry)), None);
+    }
+
+    #[test]
+    fn raw_image_bytes_are_a_miss() {
+        // Entries cached before the metadata was stored held just the image
+        let png = Bytes::from_static(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR");
+        assert_eq!(CachedImage::decode(png), None);
+    }
+}
@@ -0,0 +1,141 @@
+//! Coalesces concurrent work on the same key, so a burst of requests for an image that isn't
+//! cached yet renders it once. Everyone else waits for the first request's result.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+
+pub struct SingleFlight<T> {
+    flights: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> SingleFlight<T> {
+    /// Runs `work` unless it's already running for `key`, in which case this waits for that
+    /// run instead. If the request running it is cancelled, one of the waiters takes over.
+    pub async fn run<F, Fut>(&self, key: &str, work: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let flight = self
+            .flights
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let boarding = Boarding {
+            flights: &self.flights,
+            key,
+            flight,
+        };
+        boarding.flight.get_or_init(work).await.clone()
+    }
+
+    /// Number of keys with work running.
+    #[cfg(test)]
+    fn in_flight(&self) -> usize {
+        self.flights.lock().unwrap().len()
+    }
+}
+
+/// Takes a flight off the map once it's done, or once no request waits for it anymore.
+/// Later requests then find the result in the cache, or start over.
+struct Boarding<'a, T> {
+    flights: &'a Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+    key: &'a str,
+    flight: Arc<OnceCell<T>>,
+}
+
+impl<T> Drop for Boarding<'_, T> {
+    fn drop(&mut self) {
+        let mut flights = self.flights.lock().unwrap();
+        // Requests join under the lock, so the count can't change while checking it
+        let done = self.flight.initialized() || Arc::strong_count(&self.flight) == 2;
+        if done
+            && flights
+                .get(self.key)
+                .is_some_and(|current| Arc::ptr_eq(current, &self.flight))
+        {
+            flights.remove(self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn coalesces_concurrent_runs() {
+        let flight = Arc::new(SingleFlight::default());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+
+        let waiters = (0..8)
+            .map(|_| {
+                let (flight, runs, release) = (flight.clone(), runs.clone(), release.clone());
+                tokio::spawn(async move {
+                    flight
+                        .run("key", || async move {
+                            runs.fetch_add(1, Ordering::SeqCst);
+                            release.notified().await;
+                            42
+                        })
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        while runs.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+        for _ in 0..8 {
+            tokio::task::yield_now().await;
+        }
+        release.notify_one();
+
+        for waiter in waiters {
+            assert_eq!(waiter.await.unwrap(), 42);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(flight.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn runs_again_once_finished() {
+        let flight = SingleFlight::default();
+        assert_eq!(flight.run("key", || async { 1 }).await, 1);
+        assert_eq!(flight.run("key", || async { 2 }).await, 2);
+        assert_eq!(flight.run("other", || async { 3 }).await, 3);
+    }
+
+    #[tokio::test]
+    async fn cancelled_runs_are_taken_over() {
+        let flight = Arc::new(SingleFlight::default());
+        let leader = tokio::spawn({
+            let flight = flight.clone();
+            async move { flight.run("key", std::future::pending::<u32>).await }
+        });
+        while flight.in_flight() == 0 {
+            tokio::task::yield_now().await;
+        }
+        leader.abort();
+        let _ = leader.await;
+        assert_eq!(flight.in_flight(), 0);
+
+        assert_eq!(flight.run("key", || async { 7 }).await, 7);
+    }
+}
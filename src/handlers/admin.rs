@@ -0,0 +1,142 @@
+use crate::auth::Admin;
+use crate::cache;
+use crate::db::{image, user};
+use crate::state::AppState;
+use crate::variants;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use common::admin::{CacheStats, FlushCacheResponse, TransformStats};
+use tracing::{error, info};
+
+#[derive(Debug)]
+pub enum AdminError {
+    UserNotFound,
+    DatabaseError(String),
+    CacheError(String),
+    StorageError(String),
+}
+
+impl From<AdminError> for StatusCode {
+    fn from(error: AdminError) -> StatusCode {
+        match error {
+            AdminError::UserNotFound => StatusCode::NOT_FOUND,
+            AdminError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AdminError::CacheError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AdminError::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminError::UserNotFound => write!(f, "User not found"),
+            AdminError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            AdminError::CacheError(err) => write!(f, "Cache error: {}", err),
+            AdminError::StorageError(err) => write!(f, "Storage error: {}", err),
+        }
+    }
+}
+
+fn log_error(error: AdminError) -> StatusCode {
+    if !matches!(error, AdminError::UserNotFound) {
+        error!("Admin request failed: {}", error);
+    }
+    StatusCode::from(error)
+}
+
+pub async fn transform_stats_handler(
+    State(state): State<AppState>,
+    _admin: Admin,
+) -> Json<TransformStats> {
+    Json(state.transform_pool.stats())
+}
+
+pub async fn cache_stats_handler(
+    State(state): State<AppState>,
+    _admin: Admin,
+) -> Result<Json<CacheStats>, StatusCode> {
+    let (entries, bytes) = cache::size(&state.redis)
+        .await
+        .map_err(|e| log_error(AdminError::CacheError(e.to_string())))?;
+
+    Ok(Json(state.cache_counters.stats(entries, bytes)))
+}
+
+/// Purges both cache tiers of one image.
+async fn flush_file(state: &AppState, file_id: &str) -> Result<FlushCacheResponse, AdminError> {
+    let entries = cache::purge(&state.redis, file_id)
+        .await
+        .map_err(|e| AdminError::CacheError(e.to_string()))?;
+    let variants = variants::purge(state.storage.as_ref(), file_id)
+        .await
+        .map_err(|e| AdminError::StorageError(e.to_string()))?;
+
+    Ok(FlushCacheResponse { entries, variants })
+}
+
+pub async fn flush_cache_handler(
+    State(state): State<AppState>,
+    _admin: Admin,
+) -> Result<Json<FlushCacheResponse>, StatusCode> {
+    let entries = cache::purge_all(&state.redis)
+        .await
+        .map_err(|e| log_error(AdminError::CacheError(e.to_string())))?;
+    let variants = variants::purge_all(state.storage.as_ref())
+        .await
+        .map_err(|e| log_error(AdminError::StorageError(e.to_string())))?;
+
+    info!(
+        "Flushed the cache: {} entries, {} variants",
+        entries, variants
+    );
+    Ok(Json(FlushCacheResponse { entries, variants }))
+}
+
+pub async fn flush_file_cache_handler(
+    State(state): State<AppState>,
+    _admin: Admin,
+    Path(file_id): Path<String>,
+) -> Result<Json<FlushCacheResponse>, StatusCode> {
+    let flushed = flush_file(&state, &file_id).await.map_err(log_error)?;
+    Ok(Json(flushed))
+}
+
+pub async fn flush_user_cache_handler(
+    State(state): State<AppState>,
+    _admin: Admin,
+    Path(username): Path<String>,
+) -> Result<Json<FlushCacheResponse>, StatusCode> {
+    let user = state
+        .db
+        .user()
+        .find_unique(user::username::equals(username))
+        .exec()
+        .await
+        .map_err(|e| log_error(AdminError::DatabaseError(e.to_string())))?
+        .ok_or_else(|| log_error(AdminError::UserNotFound))?;
+
+    let images = state
+        .db
+        .image()
+        .find_many(vec![image::user_id::equals(user.id)])
+        .exec()
+        .await
+        .map_err(|e| log_error(AdminError::DatabaseError(e.to_string())))?;
+
+    let mut flushed = FlushCacheResponse {
+        entries: 0,
+        variants: 0,
+    };
+    for image in images {
+        let file = flush_file(&state, &image.file_id)
+            .await
+            .map_err(log_error)?;
+        flushed.entries += file.entries;
+        flushed.variants += file.variants;
+    }
+    Ok(Json(flushed))
+}
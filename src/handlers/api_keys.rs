@@ -0,0 +1,195 @@
+use crate::auth::{self, AllScopes, AuthError, Authorized};
+use crate::db::{self, api_key};
+use crate::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::Utc;
+use common::keys::{
+    ApiKeyInfo, CreateApiKeyRequest, CreateApiKeyResponse, ListApiKeysResponse, Scope,
+};
+use tracing::error;
+
+#[derive(Debug)]
+pub enum ApiKeyError {
+    InvalidName,
+    InvalidScopes,
+    InvalidExpiry,
+    KeyNotFound,
+    LastKey,
+    DatabaseError(String),
+}
+
+impl From<ApiKeyError> for StatusCode {
+    fn from(error: ApiKeyError) -> StatusCode {
+        match error {
+            ApiKeyError::InvalidName => StatusCode::BAD_REQUEST,
+            ApiKeyError::InvalidScopes => StatusCode::BAD_REQUEST,
+            ApiKeyError::InvalidExpiry => StatusCode::BAD_REQUEST,
+            ApiKeyError::KeyNotFound => StatusCode::NOT_FOUND,
+            ApiKeyError::LastKey => StatusCode::CONFLICT,
+            ApiKeyError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiKeyError::InvalidName => write!(f, "Key name must be 1 to 64 characters"),
+            ApiKeyError::InvalidScopes => write!(f, "Keys need at least one scope"),
+            ApiKeyError::InvalidExpiry => write!(f, "Expiry date must be in the future"),
+            ApiKeyError::KeyNotFound => write!(f, "Key not found"),
+            ApiKeyError::LastKey => {
+                write!(
+                    f,
+                    "Can't revoke the last key with every scope that never expires"
+                )
+            }
+            ApiKeyError::DatabaseError(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl From<AuthError> for ApiKeyError {
+    fn from(error: AuthError) -> ApiKeyError {
+        ApiKeyError::DatabaseError(error.to_string())
+    }
+}
+
+fn log_error(error: ApiKeyError) -> StatusCode {
+    if let ApiKeyError::DatabaseError(_) = error {
+        error!("API key error: {}", error);
+    }
+    StatusCode::from(error)
+}
+
+pub async fn create_key_handler(
+    State(state): State<AppState>,
+    user: Authorized<AllScopes>,
+    Json(payload): Json<CreateApiKeyRequest>,
+) -> Result<Json<CreateApiKeyResponse>, StatusCode> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        return Err(ApiKeyError::InvalidName.into());
+    }
+
+    let mut scopes = payload.scopes.unwrap_or_else(|| Scope::ALL.to_vec());
+    scopes.sort_by_key(|scope| Scope::ALL.iter().position(|s| s == scope));
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err(ApiKeyError::InvalidScopes.into());
+    }
+
+    if payload.expires_at.is_some_and(|at| at <= Utc::now()) {
+        return Err(ApiKeyError::InvalidExpiry.into());
+    }
+
+    let (key, api_key) =
+        auth::create_key(&state.db, &user.user_id, name, &scopes, payload.expires_at)
+            .await
+            .map_err(|e| log_error(e.into()))?;
+
+    Ok(Json(CreateApiKeyResponse {
+        id: api_key.id,
+        name: api_key.name,
+        key,
+        scopes,
+        expires_at: payload.expires_at,
+    }))
+}
+
+pub async fn list_keys_handler(
+    State(state): State<AppState>,
+    user: Authorized<AllScopes>,
+) -> Result<Json<ListApiKeysResponse>, StatusCode> {
+    let keys = state
+        .db
+        .api_key()
+        .find_many(vec![api_key::user_id::equals(user.user_id.clone())])
+        .exec()
+        .await
+        .map_err(|e| log_error(ApiKeyError::DatabaseError(e.to_string())))?;
+
+    let keys = keys
+        .into_iter()
+        .map(|key| ApiKeyInfo {
+            id: key.id,
+            name: key.name,
+            prefix: key.prefix,
+            scopes: key.scopes.into_iter().map(auth::scope_from_db).collect(),
+            created_at: key.created_at.into(),
+            last_used_at: key.last_used_at.map(Into::into),
+            expires_at: key.expires_at.map(Into::into),
+        })
+        .collect();
+
+    Ok(Json(ListApiKeysResponse { keys }))
+}
+
+/// Whether a key can manage keys for good: it has every scope and never expires.
+fn manages_keys(scopes: &[db::Scope], expires: bool) -> bool {
+    !expires
+        && Scope::ALL
+            .into_iter()
+            .all(|scope| scopes.contains(&auth::scope_to_db(scope)))
+}
+
+pub async fn revoke_key_handler(
+    State(state): State<AppState>,
+    user: Authorized<AllScopes>,
+    Path(key_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let keys = state
+        .db
+        .api_key()
+        .find_many(vec![api_key::user_id::equals(user.user_id.clone())])
+        .exec()
+        .await
+        .map_err(|e| log_error(ApiKeyError::DatabaseError(e.to_string())))?;
+
+    let Some(revoked) = keys.iter().find(|key| key.id == key_id) else {
+        return Err(ApiKeyError::KeyNotFound.into());
+    };
+
+    // Managing keys takes every scope, so one such key that never expires must remain or the
+    // user could never create or revoke keys again
+    if !keys
+        .iter()
+        .any(|key| key.id != key_id && manages_keys(&key.scopes, key.expires_at.is_some()))
+    {
+        return Err(ApiKeyError::LastKey.into());
+    }
+
+    state
+        .db
+        .api_key()
+        .delete_many(vec![
+            api_key::id::equals(key_id),
+            api_key::user_id::equals(user.user_id.clone()),
+        ])
+        .exec()
+        .await
+        .map_err(|e| log_error(ApiKeyError::DatabaseError(e.to_string())))?;
+
+    // Revoked keys must stop working right away, not when their cache entry expires
+    auth::forget_key(&state.redis, &revoked.prefix).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_unexpiring_keys_with_every_scope_manage_keys() {
+        let all = [db::Scope::Read, db::Scope::Upload, db::Scope::Delete];
+        assert!(manages_keys(&all, false));
+        assert!(!manages_keys(&all, true));
+        assert!(!manages_keys(&[db::Scope::Upload], false));
+        assert!(!manages_keys(&[], false));
+    }
+}
@@ -1,14 +1,19 @@
-use crate::db::{image, user};
+use crate::auth::{AuthError, Authorized, DeleteScope};
+use crate::cache;
+use crate::db::image;
+use crate::objects;
+use crate::quota;
 use crate::state::AppState;
+use crate::variants;
 use axum::{
     extract::{Path, State},
-    http::{HeaderMap, StatusCode},
+    http::StatusCode,
+    response::{IntoResponse, Response},
 };
 use tracing::error;
 
 #[derive(Debug)]
 pub enum DeleteImageError {
-    InvalidCredentials,
     ImageNotFound,
     NotAuthorized,
     DatabaseError(String),
@@ -18,7 +23,6 @@ pub enum DeleteImageError {
 impl From<DeleteImageError> for StatusCode {
     fn from(error: DeleteImageError) -> StatusCode {
         match error {
-            DeleteImageError::InvalidCredentials => StatusCode::UNAUTHORIZED,
             DeleteImageError::ImageNotFound => StatusCode::NOT_FOUND,
             DeleteImageError::NotAuthorized => StatusCode::FORBIDDEN,
             DeleteImageError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -27,30 +31,11 @@ impl From<DeleteImageError> for StatusCode {
     }
 }
 
-async fn validate_user(
-    db: &AppState,
-    username: &str,
-    key: &str,
-) -> Result<String, DeleteImageError> {
-    let user = db
-        .db
-        .user()
-        .find_first(vec![user::username::equals(username.to_string())])
-        .exec()
-        .await
-        .map_err(|e| DeleteImageError::DatabaseError(e.to_string()))?;
-
-    match user {
-        Some(user) if user.key == key => Ok(user.id),
-        _ => Err(DeleteImageError::InvalidCredentials),
-    }
-}
-
 async fn verify_image_ownership(
     state: &AppState,
     file_id: &str,
     user_id: &str,
-) -> Result<String, DeleteImageError> {
+) -> Result<image::Data, DeleteImageError> {
     let image = state
         .db
         .image()
@@ -60,7 +45,7 @@ async fn verify_image_ownership(
         .map_err(|e| DeleteImageError::DatabaseError(e.to_string()))?;
 
     match image {
-        Some(image) if image.user_id == user_id => Ok(image.id),
+        Some(image) if image.user_id == user_id => Ok(image),
         Some(_) => Err(DeleteImageError::NotAuthorized),
         None => Err(DeleteImageError::ImageNotFound),
     }
@@ -68,64 +53,47 @@ async fn verify_image_ownership(
 
 pub async fn delete_image_handler(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    user: Authorized<DeleteScope>,
     Path(file_id): Path<String>,
-) -> Result<StatusCode, StatusCode> {
-    // Extract username and key from headers
-    let username = headers
-        .get("X-Username")
-        .and_then(|h| h.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-
-    let key = headers
-        .get("X-Access-Key")
-        .and_then(|h| h.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-
-    // Validate user and get user ID
-    let user_id = match validate_user(&state, username, key).await {
-        Ok(id) => id,
-        Err(e) => return Err(StatusCode::from(e)),
-    };
-
+) -> Result<StatusCode, Response> {
     // Verify image ownership
-    let image_id = match verify_image_ownership(&state, &file_id, &user_id).await {
-        Ok(id) => id,
-        Err(e) => return Err(StatusCode::from(e)),
+    let image = match verify_image_ownership(&state, &file_id, &user.user_id).await {
+        Ok(image) => image,
+        Err(DeleteImageError::NotAuthorized) => return Err(AuthError::Forbidden.into_response()),
+        Err(e) => return Err(StatusCode::from(e).into_response()),
     };
 
-    // Find the object in the bucket with the file_id prefix
-    let objects = state
-        .bucket
-        .list(file_id.to_string(), None)
-        .await
-        .map_err(|e| {
-            error!("Failed to list objects: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    // Get the first object that matches our file_id
-    if let Some(list) = objects.first() {
-        if let Some(object) = list.contents.first() {
-            // Delete from storage
-            state.bucket.delete_object(&object.key).await.map_err(|e| {
-                error!("Failed to delete object from storage: {}", e);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?;
-        }
-    }
-
     // Delete from database
     state
         .db
         .image()
-        .delete(image::id::equals(image_id))
+        .delete(image::id::equals(image.id))
         .exec()
         .await
         .map_err(|e| {
             error!("Failed to delete image from database: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         })?;
 
+    // Identical uploads share one object, so only delete it with its last reference
+    if let Err(e) = objects::release(&state.db, state.storage.as_ref(), &image.object_key).await {
+        error!("Failed to release object {}: {}", image.object_key, e);
+    }
+
+    // Variants belong to this image alone, even if its original is shared
+    if let Err(e) = variants::purge(state.storage.as_ref(), &file_id).await {
+        error!("Failed to delete variants of {}: {}", file_id, e);
+    }
+
+    // Stop serving it right away, rather than once its cache entries expire
+    if let Err(e) = cache::purge(&state.redis, &file_id).await {
+        error!("Failed to purge cached entries of {}: {}", file_id, e);
+    }
+
+    // Give the space back to the user
+    if let Err(e) = quota::release(&state.db, &user.user_id, image.size.max(0) as u64).await {
+        error!("Failed to release quota for user {}: {}", user.user_id, e);
+    }
+
     Ok(StatusCode::NO_CONTENT)
 }
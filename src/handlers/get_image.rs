@@ -1,47 +1,97 @@
+use crate::cache::{self, CachedImage};
+use crate::db::{self, PrismaClient};
+use crate::range::{self, RangeNotSatisfiable};
+use crate::signing::{UrlSigner, SIGNATURE_PARAM};
 use crate::state::AppState;
+use crate::storage::StorageError;
+use crate::transform::{Pipeline, PoolError, TransformError};
+use crate::variants;
 use axum::{
-    extract::{Path, Query, State},
-    http::{HeaderMap, HeaderValue, StatusCode},
+    body::Body,
+    extract::{Path, Query, RawQuery, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
 };
 use bytes::Bytes;
-use fred::{
-    error::RedisError,
-    prelude::{KeysInterface, RedisPool},
-    types::{Expiration, SetOptions},
-};
-use image::{ImageFormat, ImageOutputFormat};
-use s3::Bucket;
+use chrono::{DateTime, Utc};
+use common::config::{CacheControlConfig, LimitsConfig, TransformConfig};
+use common::transform::TransformParams;
+use common::upload::VariantInfo;
 use serde::Deserialize;
-use std::io::Cursor;
-use tracing::{debug, error};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, time::Duration};
+use tracing::{debug, error, warn};
 
 #[derive(Debug, Deserialize)]
-pub struct ImageParams {
-    #[serde(default)]
-    width: Option<u32>,
-    #[serde(default)]
-    height: Option<u32>,
+pub struct PresetParam {
     #[serde(default)]
-    quality: Option<u8>,
-    #[serde(default)]
-    format: Option<String>,
+    preset: Option<String>,
 }
 
-#[derive(Debug)]
+/// Fills in the parameters of the requested preset, explicit parameters take precedence.
+fn apply_preset(
+    params: TransformParams,
+    preset: Option<&str>,
+    presets: &HashMap<String, TransformParams>,
+) -> Result<TransformParams, GetImageError> {
+    match preset {
+        Some(name) => Ok(params.or(presets.get(name).ok_or(GetImageError::UnknownPreset)?)),
+        None => Ok(params),
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum GetImageError {
     NotFound,
+    UnknownPreset,
+    InvalidParams(String),
+    SignatureRequired,
     DatabaseError(String),
     CompressionError(String),
     CacheError(String),
+    StorageError(String),
+    Busy,
 }
 
 impl From<GetImageError> for StatusCode {
     fn from(error: GetImageError) -> StatusCode {
         match error {
             GetImageError::NotFound => StatusCode::NOT_FOUND,
+            GetImageError::UnknownPreset => StatusCode::BAD_REQUEST,
+            GetImageError::InvalidParams(_) => StatusCode::BAD_REQUEST,
+            GetImageError::SignatureRequired => StatusCode::FORBIDDEN,
             GetImageError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             GetImageError::CompressionError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             GetImageError::CacheError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            GetImageError::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            GetImageError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl From<TransformError> for GetImageError {
+    fn from(error: TransformError) -> GetImageError {
+        match error {
+            TransformError::InvalidParams(err) => GetImageError::InvalidParams(err),
+            error => GetImageError::CompressionError(error.to_string()),
+        }
+    }
+}
+
+impl From<StorageError> for GetImageError {
+    fn from(error: StorageError) -> GetImageError {
+        match error {
+            StorageError::NotFound => GetImageError::NotFound,
+            error => GetImageError::StorageError(error.to_string()),
+        }
+    }
+}
+
+impl From<PoolError> for GetImageError {
+    fn from(error: PoolError) -> GetImageError {
+        match error {
+            PoolError::Busy => GetImageError::Busy,
+            error => GetImageError::CompressionError(error.to_string()),
         }
     }
 }
@@ -50,201 +100,1071 @@ impl std::fmt::Display for GetImageError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             GetImageError::NotFound => write!(f, "Image not found"),
+            GetImageError::UnknownPreset => write!(f, "Unknown preset"),
+            GetImageError::InvalidParams(err) => write!(f, "Invalid parameters: {}", err),
+            GetImageError::SignatureRequired => {
+                write!(f, "Transforms need a signed URL or a preset")
+            }
             GetImageError::DatabaseError(err) => write!(f, "Database error: {}", err),
             GetImageError::CompressionError(err) => write!(f, "Compression error: {}", err),
             GetImageError::CacheError(err) => write!(f, "Cache error: {}", err),
+            GetImageError::StorageError(err) => write!(f, "Storage error: {}", err),
+            GetImageError::Busy => write!(f, "Too many transforms queued"),
         }
     }
 }
 
-fn generate_cache_key(file_id: &str, params: &ImageParams) -> String {
+/// Signed requests are cached apart, so unsigned ones can never be served a private image.
+/// Negotiated formats are part of the key, `format=auto` has a variant per format.
+fn generate_cache_key(
+    file_id: &str,
+    params: &TransformParams,
+    negotiated: Option<&str>,
+    signed: bool,
+) -> String {
     format!(
-        "img:{}:w{:?}:h{:?}:q{:?}:f{:?}",
-        file_id, params.width, params.height, params.quality, params.format
+        "{}{}:{:?}{}{}",
+        cache::KEY_PREFIX,
+        file_id,
+        params,
+        negotiated
+            .map(|format| format!(":as-{}", format))
+            .unwrap_or_default(),
+        if signed { ":signed" } else { "" }
     )
 }
 
-async fn get_from_cache(pool: &RedisPool, cache_key: &str) -> Result<Option<Bytes>, RedisError> {
-    debug!("Attempting to get image from cache with key: {}", cache_key);
-    let cached = pool.get::<Option<Bytes>, _>(cache_key).await?;
-    Ok(cached.map(Bytes::from))
+/// ETag and last-modified date of a variant. The ETag is strong, from the content hash of
+/// the original and everything that shapes the variant. The date is truncated to whole
+/// seconds like cached entries, so hits and misses send the same headers.
+fn validators(hash: &str, created_at: DateTime<Utc>, cache_key: &str) -> (String, DateTime<Utc>) {
+    let digest = Sha256::digest(format!("{}:{}", hash, cache_key));
+    let etag = format!("\"{}\"", hex::encode(&digest[..16]));
+    let last_modified = DateTime::from_timestamp(created_at.timestamp(), 0).unwrap_or(created_at);
+    (etag, last_modified)
 }
 
-async fn set_in_cache(
-    pool: &RedisPool,
+/// Images never change once uploaded, so responses are immutable unless a preset, whose
+/// definition can change, picked the transform. Signed URLs are for one client.
+fn cache_control(config: &CacheControlConfig, signed: bool, preset: bool) -> String {
+    let scope = if signed { "private" } else { "public" };
+    match preset {
+        true => format!("{}, max-age={}", scope, config.max_age),
+        false => format!("{}, max-age={}, immutable", scope, config.immutable_max_age),
+    }
+}
+
+/// The original an image response is rendered from on a cache miss.
+struct Source {
+    data: Bytes,
+    content_type: String,
+    hash: String,
+    created_at: DateTime<Utc>,
+}
+
+/// Runs the pipeline over the original, or passes it through if there's nothing to do.
+fn render(
+    source: Source,
+    pipeline: Option<&Pipeline>,
     cache_key: &str,
-    data: &[u8],
-    ttl_secs: i64,
-) -> Result<(), RedisError> {
-    debug!("Caching image with key: {}", cache_key);
-    pool.set(
-        cache_key,
+    limits: &LimitsConfig,
+) -> Result<CachedImage, GetImageError> {
+    let (data, content_type) = match pipeline {
+        Some(pipeline) => {
+            let (data, content_type) = pipeline.run(&source.data, limits)?;
+            (Bytes::from(data), content_type.to_string())
+        }
+        None => (source.data, source.content_type),
+    };
+
+    let (etag, last_modified) = validators(&source.hash, source.created_at, cache_key);
+    Ok(CachedImage {
+        content_type,
+        etag,
+        last_modified,
         data,
-        Some(Expiration::EX(ttl_secs)),
-        Some(SetOptions::NX),
-        false,
-    )
-    .await
+    })
 }
 
-async fn find_image_with_extension(
-    bucket: &Bucket,
-    file_id: &str,
-) -> Result<(String, Bucket), GetImageError> {
-    debug!("Searching for image with file_id: {}", file_id);
+/// Whether the client's copy is current, going by `If-None-Match`, or by
+/// `If-Modified-Since` when there's no ETag to compare.
+fn is_not_modified(request_headers: &HeaderMap, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    if let Some(if_none_match) = request_headers.get(header::IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        // If-None-Match uses weak comparison
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
 
-    // List all objects in the bucket with the file_id prefix
-    let objects = bucket.list(file_id.to_string(), None).await.map_err(|e| {
-        error!("Failed to list objects: {}", e);
-        GetImageError::DatabaseError(e.to_string())
-    })?;
+    request_headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+        .is_some_and(|since| last_modified <= since)
+}
 
-    debug!("Found {} objects with prefix {}", objects.len(), file_id);
+/// Headers of both full and `304 Not Modified` responses.
+fn validator_headers(
+    etag: &str,
+    last_modified: DateTime<Utc>,
+    negotiated: bool,
+    cache_control: &str,
+) -> Result<HeaderMap, StatusCode> {
+    let mut headers = HeaderMap::new();
+    // Shared caches must not hand a format to a client that didn't ask for it
+    if negotiated {
+        headers.insert(header::VARY, HeaderValue::from_static("Accept"));
+    }
+    headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(etag).map_err(|e| {
+            error!("Invalid ETag header value: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+    );
+    headers.insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(
+            &last_modified
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        )
+        .map_err(|e| {
+            error!("Invalid last-modified header value: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_str(cache_control).map_err(|e| {
+            error!("Invalid cache-control header value: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+    );
+    Ok(headers)
+}
+
+/// Headers of an image response, the same whether it came from the cache or not.
+fn response_headers(
+    image: &CachedImage,
+    negotiated: bool,
+    cache_control: &str,
+) -> Result<HeaderMap, StatusCode> {
+    let mut headers =
+        validator_headers(&image.etag, image.last_modified, negotiated, cache_control)?;
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(&image.content_type).map_err(|e| {
+            error!("Invalid content-type header value: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(image.data.len()));
+    Ok(headers)
+}
+
+/// A full response, or `304 Not Modified` if the client's copy is current.
+fn respond(
+    image: CachedImage,
+    request_headers: &HeaderMap,
+    negotiated: bool,
+    cache_control: &str,
+) -> Result<(StatusCode, HeaderMap, Bytes), StatusCode> {
+    if is_not_modified(request_headers, &image.etag, image.last_modified) {
+        let headers =
+            validator_headers(&image.etag, image.last_modified, negotiated, cache_control)?;
+        return Ok((StatusCode::NOT_MODIFIED, headers, Bytes::new()));
+    }
 
-    // Find the first object that starts with our file_id
-    let object = objects
-        .first()
-        .ok_or_else(|| {
-            error!("No objects found with prefix {}", file_id);
-            GetImageError::NotFound
+    let headers = response_headers(&image, negotiated, cache_control)?;
+    Ok((StatusCode::OK, headers, image.data))
+}
+
+async fn find_image(db: &PrismaClient, file_id: &str) -> Result<db::image::Data, GetImageError> {
+    debug!("Looking up image with file_id: {}", file_id);
+
+    db.image()
+        .find_unique(db::image::file_id::equals(file_id.to_string()))
+        .exec()
+        .await
+        .map_err(|e| {
+            error!("Failed to look up image: {}", e);
+            GetImageError::DatabaseError(e.to_string())
         })?
-        .contents
-        .first()
-        .ok_or_else(|| {
-            error!("Empty contents for prefix {}", file_id);
-            GetImageError::NotFound
+        .ok_or(GetImageError::NotFound)
+}
+
+/// What a request resolves to before the image is looked up.
+struct Prepared {
+    signed: bool,
+    has_transforms: bool,
+    pipeline: Pipeline,
+    negotiated: Option<&'static str>,
+    cache_key: String,
+    cache_control: String,
+}
+
+fn prepare(
+    state: &AppState,
+    file_id: &str,
+    params: TransformParams,
+    preset: Option<&str>,
+    query: Option<String>,
+    request_headers: &HeaderMap,
+) -> Result<Prepared, StatusCode> {
+    // Signed URLs are checked up front, they don't need the database
+    let query = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .into_owned()
+        .collect::<Vec<_>>();
+    let signed = query.iter().any(|(name, _)| name == SIGNATURE_PARAM);
+    if signed {
+        state.signer.verify(file_id, &query).map_err(|e| {
+            debug!("Rejected signed URL for {}: {}", file_id, e);
+            StatusCode::from(e)
         })?;
+    }
+
+    // Arbitrary transforms can be locked down to signed URLs, presets stay open to everyone
+    if state.transforms.require_signature && !signed && params != TransformParams::default() {
+        debug!("Rejected unsigned transform of {}", file_id);
+        return Err(GetImageError::SignatureRequired.into());
+    }
+    let params =
+        apply_preset(params, preset, &state.transforms.presets).map_err(StatusCode::from)?;
+    let mut pipeline = Pipeline::new(&params, &state.limits).map_err(|e| {
+        debug!("Rejected transform of {}: {}", file_id, e);
+        StatusCode::from(GetImageError::from(e))
+    })?;
 
-    debug!("Found object: {}", object.key);
+    let accept = request_headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok());
+    let negotiated = pipeline.negotiate(accept);
 
-    Ok((object.key.clone(), bucket.clone()))
+    // Generate cache key based on file_id and processing parameters
+    let cache_key = generate_cache_key(file_id, &params, negotiated, signed);
+    let cache_control = cache_control(&state.cache_control, signed, preset.is_some());
+
+    Ok(Prepared {
+        signed,
+        has_transforms: params != TransformParams::default(),
+        pipeline,
+        negotiated,
+        cache_key,
+        cache_control,
+    })
 }
 
-fn process_image(data: &[u8], params: &ImageParams) -> Result<(Vec<u8>, String), GetImageError> {
-    let img = image::load_from_memory(data)
-        .map_err(|e| GetImageError::CompressionError(format!("Failed to load image: {}", e)))?;
+/// Looks up the image, hiding private ones from requests that aren't signed.
+async fn find_visible_image(
+    state: &AppState,
+    file_id: &str,
+    signed: bool,
+) -> Result<db::image::Data, StatusCode> {
+    let image = find_image(&state.db, file_id)
+        .await
+        .map_err(StatusCode::from)?;
 
-    let processed = if params.width.is_some() || params.height.is_some() {
-        let width = params.width.unwrap_or(img.width());
-        let height = params.height.unwrap_or(img.height());
-        img.resize(width, height, image::imageops::FilterType::Lanczos3)
-    } else {
-        img
+    // Private images are only served through signed URLs, and don't reveal they exist otherwise
+    if !signed && image.visibility == db::Visibility::Private {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(image)
+}
+
+/// Whether a range may be served, which `If-Range` only allows if the client's copy is the
+/// current one. Unlike `If-None-Match`, this uses strong comparison.
+fn is_range_current(request_headers: &HeaderMap, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    let Some(if_range) = request_headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(if_range) = if_range.to_str() else {
+        return false;
     };
 
-    // Determine output format
-    let format = match params.format.as_deref() {
-        Some("jpeg") | Some("jpg") => (
-            ImageOutputFormat::Jpeg(params.quality.unwrap_or(80)),
-            "image/jpeg",
-        ),
-        Some("png") => (ImageOutputFormat::Png, "image/png"),
-        Some("webp") => (ImageOutputFormat::WebP, "image/webp"),
-        _ => {
-            // Default to original format or JPEG
-            match image::guess_format(data).unwrap_or(ImageFormat::Jpeg) {
-                ImageFormat::Jpeg => (
-                    ImageOutputFormat::Jpeg(params.quality.unwrap_or(80)),
-                    "image/jpeg",
-                ),
-                ImageFormat::Png => (ImageOutputFormat::Png, "image/png"),
-                ImageFormat::WebP => (ImageOutputFormat::WebP, "image/webp"),
-                _ => (
-                    ImageOutputFormat::Jpeg(params.quality.unwrap_or(80)),
-                    "image/jpeg",
-                ),
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+    DateTime::parse_from_rfc2822(if_range).is_ok_and(|date| date == last_modified)
+}
+
+/// Only originals are served in ranges, variants are always rendered whole.
+fn advertise_ranges(headers: &mut HeaderMap, original: bool) {
+    if original {
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+}
+
+fn header_value(value: &str, name: &str) -> Result<HeaderValue, StatusCode> {
+    HeaderValue::from_str(value).map_err(|e| {
+        error!("Invalid {} header value: {}", name, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Headers of a full response with an original, from what the database knows about it.
+fn original_headers(
+    image: &db::image::Data,
+    etag: &str,
+    last_modified: DateTime<Utc>,
+    cache_control: &str,
+) -> Result<HeaderMap, StatusCode> {
+    let mut headers = validator_headers(etag, last_modified, false, cache_control)?;
+    advertise_ranges(&mut headers, true);
+    headers.insert(
+        header::CONTENT_TYPE,
+        header_value(&image.content_type, "content-type")?,
+    );
+    headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(u64::try_from(image.size).unwrap_or_default()),
+    );
+    Ok(headers)
+}
+
+fn storage_error(error: StorageError) -> StatusCode {
+    error!("Failed to get object: {}", error);
+    match error {
+        StorageError::NotFound => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Renders the image and caches the result. With the render lock enabled, an instance that
+/// finds another one rendering the same variant waits for its result instead.
+async fn render_locked(
+    state: &AppState,
+    image: db::image::Data,
+    pipeline: Option<Pipeline>,
+    cache_key: &str,
+) -> Result<CachedImage, GetImageError> {
+    let mut locked = false;
+    if state.cache.render_lock {
+        let timeout = state.cache.render_lock_timeout;
+        match cache::lock(&state.redis, cache_key, timeout).await {
+            Ok(true) => locked = true,
+            Ok(false) => {
+                debug!("Waiting for another instance to render {}", cache_key);
+                let waited = cache::wait_for(&state.redis, cache_key, Duration::from_secs(timeout));
+                if let Some(cached) = waited.await {
+                    return Ok(cached);
+                }
             }
+            Err(e) => error!("Failed to take render lock: {}", e),
         }
+    }
+
+    let rendered = render_and_cache(state, image, pipeline, cache_key).await;
+    if locked {
+        if let Err(e) = cache::unlock(&state.redis, cache_key).await {
+            error!("Failed to release render lock: {}", e);
+        }
+    }
+    rendered
+}
+
+async fn render_and_cache(
+    state: &AppState,
+    image: db::image::Data,
+    pipeline: Option<Pipeline>,
+    cache_key: &str,
+) -> Result<CachedImage, GetImageError> {
+    // Variants may have been rendered before, and outlived their Redis entry in storage
+    let store_variant = pipeline.is_some() && state.cache.store_variants;
+    if store_variant {
+        let stored = variants::get(
+            state.storage.as_ref(),
+            &image.file_id,
+            cache_key,
+            state.cache.storage_ttl,
+        )
+        .await;
+        match stored {
+            Ok(Some(stored)) => {
+                debug!("Found stored variant for key: {}", cache_key);
+                state.cache_counters.stored_hit();
+                cache_rendered(state, &image.id, &image.file_id, cache_key, &stored, false).await;
+                return Ok(stored);
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to get stored variant: {}", e),
+        }
+    }
+
+    state.cache_counters.miss();
+    let data = state.storage.get(&image.object_key).await?;
+
+    // Process image if any parameters are specified, otherwise serve the original as uploaded
+    let source = Source {
+        data,
+        content_type: image.content_type,
+        hash: image.hash,
+        created_at: image.created_at.into(),
+    };
+    let rendered = match pipeline {
+        Some(pipeline) => {
+            let cache_key = cache_key.to_string();
+            let limits = state.limits.clone();
+            state
+                .transform_pool
+                .run(move || render(source, Some(&pipeline), &cache_key, &limits))
+                .await??
+        }
+        None => render(source, None, cache_key, &state.limits)?,
     };
 
-    let mut buffer = Vec::new();
-    let mut cursor = Cursor::new(&mut buffer);
-    processed
-        .write_to(&mut cursor, format.0)
-        .map_err(|e| GetImageError::CompressionError(format!("Failed to encode image: {}", e)))?;
+    cache_rendered(
+        state,
+        &image.id,
+        &image.file_id,
+        cache_key,
+        &rendered,
+        store_variant,
+    )
+    .await;
+    Ok(rendered)
+}
+
+/// Caches a rendered image in Redis, and in storage too if `store_variant` is set.
+///
+/// The image may have been deleted while it was rendering, after its entries were purged.
+/// It's looked up again once the new entries are written, and they're dropped if it's gone.
+async fn cache_rendered(
+    state: &AppState,
+    image_id: &str,
+    file_id: &str,
+    cache_key: &str,
+    rendered: &CachedImage,
+    store_variant: bool,
+) {
+    if store_variant {
+        if let Err(e) = variants::put(state.storage.as_ref(), file_id, cache_key, rendered).await {
+            error!("Failed to store variant: {}", e);
+        }
+    }
+    cache_in_redis(state, file_id, cache_key, rendered).await;
+
+    let current = state
+        .db
+        .image()
+        .find_unique(db::image::id::equals(image_id.to_string()))
+        .exec()
+        .await;
+    match current {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            debug!(
+                "{} was deleted while rendering, dropping its entries",
+                file_id
+            );
+            if let Err(e) = cache::purge(&state.redis, file_id).await {
+                error!("Failed to purge cached entries of {}: {}", file_id, e);
+            }
+            if let Err(e) = variants::purge(state.storage.as_ref(), file_id).await {
+                error!("Failed to delete variants of {}: {}", file_id, e);
+            }
+        }
+        Err(e) => error!("Failed to look up image {}: {}", file_id, e),
+    }
+}
+
+/// Caches the processed result, unless it's too large to be worth keeping in memory.
+async fn cache_in_redis(state: &AppState, file_id: &str, cache_key: &str, image: &CachedImage) {
+    if image.data.len() as u64 > state.cache.max_entry_size {
+        return;
+    }
+    let ttl = i64::try_from(state.cache.redis_ttl).unwrap_or(i64::MAX);
+    if let Err(e) = cache::set(&state.redis, file_id, cache_key, image, ttl).await {
+        error!("Failed to cache image: {}", e);
+    }
+}
+
+/// Accept headers variants with `format=auto` are rendered ahead for, one per format they
+/// negotiate, so each kind of client finds its variant cached.
+const EAGER_ACCEPTS: [Option<&str>; 3] = [Some("image/avif"), Some("image/webp"), None];
+
+/// The configured eager variants of an image, with the URLs they're requested at. Private
+/// images are only served through signed URLs, so theirs are signed with the default TTL.
+pub fn eager_variants(
+    transforms: &TransformConfig,
+    signer: &UrlSigner,
+    file_id: &str,
+    visibility: db::Visibility,
+) -> Vec<VariantInfo> {
+    let expires_at = (visibility == db::Visibility::Private)
+        .then(|| {
+            let ttl = i64::try_from(signer.default_ttl).unwrap_or(i64::MAX / 2);
+            DateTime::from_timestamp(Utc::now().timestamp() + ttl, 0)
+        })
+        .flatten();
+
+    transforms
+        .eager
+        .iter()
+        .filter(|preset| transforms.presets.contains_key(*preset))
+        .map(|preset| {
+            let query = match expires_at {
+                Some(expires_at) => signer.signed_query(
+                    file_id,
+                    vec![("preset".to_string(), preset.clone())],
+                    expires_at.timestamp(),
+                ),
+                None => format!("preset={}", preset),
+            };
+            VariantInfo {
+                preset: preset.clone(),
+                url: format!("/images/{}?{}", file_id, query),
+                expires_at,
+            }
+        })
+        .collect()
+}
+
+/// Renders the eager variants of a new image into both cache tiers, under the same keys
+/// requests for them use. Runs in the background after an upload, variants that can't be
+/// rendered now are rendered on their first request as usual.
+pub async fn render_eager_variants(state: AppState, image: db::image::Data) {
+    // Private images are only served through signed URLs, which are cached apart
+    let signed = image.visibility == db::Visibility::Private;
+
+    for preset in &state.transforms.eager {
+        let Ok(params) = apply_preset(
+            TransformParams::default(),
+            Some(preset),
+            &state.transforms.presets,
+        ) else {
+            warn!("Eager variant {} is not a configured preset", preset);
+            continue;
+        };
+
+        let mut rendered = Vec::new();
+        for accept in EAGER_ACCEPTS {
+            let mut pipeline = match Pipeline::new(&params, &state.limits) {
+                Ok(pipeline) => pipeline,
+                Err(e) => {
+                    warn!("Eager variant {} is invalid: {}", preset, e);
+                    break;
+                }
+            };
+            let negotiated = pipeline.negotiate(accept);
+            if rendered.contains(&negotiated) {
+                continue;
+            }
+            rendered.push(negotiated);
 
-    Ok((buffer, format.1.to_string()))
+            let cache_key = generate_cache_key(&image.file_id, &params, negotiated, signed);
+            let _slot = state.transform_pool.eager_slot().await;
+            let result = state
+                .renders
+                .run(&cache_key, || {
+                    render_locked(&state, image.clone(), Some(pipeline), &cache_key)
+                })
+                .await;
+            match result {
+                Ok(_) => debug!("Rendered eager variant: {}", cache_key),
+                Err(GetImageError::Busy) => {
+                    debug!("Skipped eager variant {}, the queue is full", cache_key)
+                }
+                Err(e) => error!("Failed to render eager variant {}: {}", cache_key, e),
+            }
+        }
+    }
 }
 
 pub async fn get_image_handler(
     State(state): State<AppState>,
     Path(file_id): Path<String>,
-    Query(params): Query<ImageParams>,
-) -> Result<(HeaderMap, Bytes), StatusCode> {
+    Query(params): Query<TransformParams>,
+    Query(PresetParam { preset }): Query<PresetParam>,
+    RawQuery(query): RawQuery,
+    request_headers: HeaderMap,
+) -> Result<Response, StatusCode> {
     debug!("Getting image with file_id: {}", file_id);
-    // Generate cache key based on file_id and processing parameters
-    let cache_key = generate_cache_key(&file_id, &params);
 
-    // Try to get from cache first
-    if let Ok(Some(cached_data)) = get_from_cache(&state.redis, &cache_key).await {
-        debug!("Cache hit for key: {}", cache_key);
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            axum::http::header::CONTENT_TYPE,
-            HeaderValue::from_str("image/jpeg").unwrap(),
+    let request = prepare(
+        &state,
+        &file_id,
+        params,
+        preset.as_deref(),
+        query,
+        &request_headers,
+    )?;
+    let original = !request.has_transforms;
+    let range = request_headers
+        .get(header::RANGE)
+        .filter(|_| original)
+        .and_then(|range| range.to_str().ok());
+
+    // Try to get from cache first, ranges are read from storage instead
+    if range.is_none() {
+        if let Ok(Some(cached)) = cache::get(&state.redis, &request.cache_key).await {
+            debug!("Cache hit for key: {}", request.cache_key);
+            state.cache_counters.hit();
+            let mut response = respond(
+                cached,
+                &request_headers,
+                request.negotiated.is_some(),
+                &request.cache_control,
+            )?;
+            advertise_ranges(&mut response.1, original);
+            return Ok(response.into_response());
+        }
+    }
+
+    // If not in cache, get from storage
+    let image = find_visible_image(&state, &file_id, request.signed).await?;
+
+    // Revalidations don't need the original
+    let (etag, last_modified) =
+        validators(&image.hash, image.created_at.into(), &request.cache_key);
+    if is_not_modified(&request_headers, &etag, last_modified) {
+        let headers = validator_headers(
+            &etag,
+            last_modified,
+            request.negotiated.is_some(),
+            &request.cache_control,
+        )?;
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    // Serve only the requested bytes, unless the client's copy is outdated and needs all of them
+    if let Some(range) = range.filter(|_| is_range_current(&request_headers, &etag, last_modified))
+    {
+        let size = u64::try_from(image.size).unwrap_or_default();
+        match range::parse(range, size) {
+            Ok(Some(range)) => {
+                let stream = state
+                    .storage
+                    .get_range(&image.object_key, range.start, range.end)
+                    .await
+                    .map_err(storage_error)?;
+
+                let mut headers =
+                    original_headers(&image, &etag, last_modified, &request.cache_control)?;
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    header_value(&range.content_range(size), "content-range")?,
+                );
+                headers.insert(
+                    header::CONTENT_LENGTH,
+                    HeaderValue::from(range.end - range.start + 1),
+                );
+                return Ok((
+                    StatusCode::PARTIAL_CONTENT,
+                    headers,
+                    Body::from_stream(stream),
+                )
+                    .into_response());
+            }
+            Err(RangeNotSatisfiable) => {
+                let mut headers = HeaderMap::new();
+                advertise_ranges(&mut headers, original);
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    header_value(&format!("bytes */{}", size), "content-range")?,
+                );
+                return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+            }
+            Ok(None) => {}
+        }
+    }
+
+    // Large originals go straight from storage to the client, they'd crowd out the cache
+    let size = u64::try_from(image.size).unwrap_or_default();
+    if original && size > state.cache.max_entry_size {
+        let stream = state
+            .storage
+            .get_stream(&image.object_key)
+            .await
+            .map_err(storage_error)?;
+        let headers = original_headers(&image, &etag, last_modified, &request.cache_control)?;
+        return Ok((StatusCode::OK, headers, Body::from_stream(stream)).into_response());
+    }
+
+    // Concurrent requests for the same variant wait for a single render
+    let flight = state.renders.run(&request.cache_key, || {
+        let pipeline = request.has_transforms.then_some(request.pipeline);
+        render_locked(&state, image, pipeline, &request.cache_key)
+    });
+    let rendered = match flight.await {
+        Ok(rendered) => rendered,
+        Err(GetImageError::Busy) => {
+            debug!("Turned away transform of {}, the queue is full", file_id);
+            let retry_after = HeaderValue::from(state.transform_pool.retry_after);
+            return Ok((
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, retry_after)],
+            )
+                .into_response());
+        }
+        Err(e) => {
+            if let GetImageError::CompressionError(_) | GetImageError::StorageError(_) = e {
+                error!("Image processing error: {}", e);
+            }
+            return Err(e.into());
+        }
+    };
+
+    let mut response = respond(
+        rendered,
+        &request_headers,
+        request.negotiated.is_some(),
+        &request.cache_control,
+    )?;
+    advertise_ranges(&mut response.1, original);
+    Ok(response.into_response())
+}
+
+/// Answers with the headers a GET would, without reading the image from storage. Originals
+/// get theirs from the database, variants only have their validators until they're cached.
+pub async fn head_image_handler(
+    State(state): State<AppState>,
+    Path(file_id): Path<String>,
+    Query(params): Query<TransformParams>,
+    Query(PresetParam { preset }): Query<PresetParam>,
+    RawQuery(query): RawQuery,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap), StatusCode> {
+    debug!("Getting image headers with file_id: {}", file_id);
+
+    let request = prepare(
+        &state,
+        &file_id,
+        params,
+        preset.as_deref(),
+        query,
+        &request_headers,
+    )?;
+    let original = !request.has_transforms;
+
+    if let Ok(Some(cached)) = cache::get(&state.redis, &request.cache_key).await {
+        debug!("Cache hit for key: {}", request.cache_key);
+        let (status, mut headers, _) = respond(
+            cached,
+            &request_headers,
+            request.negotiated.is_some(),
+            &request.cache_control,
+        )?;
+        advertise_ranges(&mut headers, original);
+        return Ok((status, headers));
+    }
+
+    let image = find_visible_image(&state, &file_id, request.signed).await?;
+
+    let (etag, last_modified) =
+        validators(&image.hash, image.created_at.into(), &request.cache_key);
+    let headers = validator_headers(
+        &etag,
+        last_modified,
+        request.negotiated.is_some(),
+        &request.cache_control,
+    )?;
+    if is_not_modified(&request_headers, &etag, last_modified) {
+        return Ok((StatusCode::NOT_MODIFIED, headers));
+    }
+
+    if original {
+        let headers = original_headers(&image, &etag, last_modified, &request.cache_control)?;
+        return Ok((StatusCode::OK, headers));
+    }
+    Ok((StatusCode::OK, headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::config::SigningConfig;
+    use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    const SOURCE_FORMATS: [ImageFormat; 6] = [
+        ImageFormat::Jpeg,
+        ImageFormat::Png,
+        ImageFormat::WebP,
+        ImageFormat::Gif,
+        ImageFormat::Bmp,
+        ImageFormat::Tiff,
+    ];
+
+    fn limits() -> LimitsConfig {
+        LimitsConfig {
+            max_file_size: 1 << 20,
+            max_files_per_request: 1,
+            upload_timeout: 600,
+            max_width: 1000,
+            max_height: 1000,
+            max_pixels: 1_000_000,
+            quota_bytes: None,
+            quota_images: None,
+        }
+    }
+
+    fn original(format: ImageFormat) -> Vec<u8> {
+        let img = RgbImage::from_fn(8, 8, |x, y| Rgb([(x * 32) as u8, (y * 32) as u8, 128]));
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    /// Serves `params` from an original in `format` on a miss, then from the entry the miss
+    /// cached, and checks both responses match. Returns the headers and the body.
+    fn serve(
+        format: ImageFormat,
+        params: TransformParams,
+        accept: Option<&str>,
+    ) -> (HeaderMap, Bytes) {
+        let data = original(format);
+        let mut pipeline = Pipeline::new(&params, &limits()).unwrap();
+        let negotiated = pipeline.negotiate(accept);
+        let cache_key = generate_cache_key("file", &params, negotiated, false);
+
+        let source = Source {
+            data: Bytes::from(data),
+            content_type: format.to_mime_type().to_string(),
+            hash: "hash".to_string(),
+            created_at: Utc::now(),
+        };
+        let has_transforms = params != TransformParams::default();
+        let miss = render(
+            source,
+            has_transforms.then_some(&pipeline),
+            &cache_key,
+            &limits(),
+        )
+        .unwrap();
+        let miss_headers = response_headers(&miss, negotiated.is_some(), "public").unwrap();
+
+        let hit = CachedImage::decode(Bytes::from(miss.encode())).unwrap();
+        let hit_headers = response_headers(&hit, negotiated.is_some(), "public").unwrap();
+
+        assert_eq!(hit_headers, miss_headers);
+        assert_eq!(hit.data, miss.data);
+        assert_eq!(
+            hit_headers[header::CONTENT_LENGTH],
+            hit.data.len().to_string()
         );
+        (hit_headers, hit.data)
+    }
+
+    fn content_type(headers: &HeaderMap) -> &str {
+        headers[header::CONTENT_TYPE].to_str().unwrap()
+    }
+
+    fn with_format(format: &str) -> TransformParams {
+        TransformParams {
+            format: Some(format.to_string()),
+            ..TransformParams::default()
+        }
+    }
+
+    #[test]
+    fn originals_keep_their_content_type() {
+        for format in SOURCE_FORMATS {
+            let (headers, body) = serve(format, TransformParams::default(), None);
+            assert_eq!(content_type(&headers), format.to_mime_type());
+            assert_eq!(body, original(format));
+        }
+    }
+
+    #[test]
+    fn resized_variants_keep_encodable_source_formats() {
+        for format in SOURCE_FORMATS {
+            let params = TransformParams {
+                width: Some(4),
+                ..TransformParams::default()
+            };
+            let (headers, body) = serve(format, params, None);
+
+            let expected = match format {
+                ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP => format,
+                // GIFs always decode with an alpha channel
+                ImageFormat::Gif => ImageFormat::Png,
+                _ => ImageFormat::Jpeg,
+            };
+            assert_eq!(content_type(&headers), expected.to_mime_type());
+            assert_eq!(image::guess_format(&body).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn converted_variants_have_the_requested_content_type() {
+        for (name, mime) in [
+            ("jpeg", "image/jpeg"),
+            ("png", "image/png"),
+            ("webp", "image/webp"),
+            ("avif", "image/avif"),
+        ] {
+            for format in SOURCE_FORMATS {
+                let (headers, _) = serve(format, with_format(name), None);
+                assert_eq!(content_type(&headers), mime);
+                assert!(!headers.contains_key(header::VARY));
+            }
+        }
+    }
+
+    #[test]
+    fn negotiated_variants_have_the_negotiated_content_type() {
+        for (accept, mime) in [
+            (Some("image/avif,image/webp,*/*"), "image/avif"),
+            (Some("image/webp,*/*"), "image/webp"),
+            (Some("*/*"), "image/jpeg"),
+            (None, "image/jpeg"),
+        ] {
+            let (headers, _) = serve(ImageFormat::Png, with_format("auto"), accept);
+            assert_eq!(content_type(&headers), mime);
+            assert_eq!(headers[header::VARY], "Accept");
+        }
+    }
+
+    #[test]
+    fn variants_have_distinct_etags() {
+        let (webp, _) = serve(ImageFormat::Png, with_format("webp"), None);
+        let (png, _) = serve(ImageFormat::Png, with_format("png"), None);
+        let (original, _) = serve(ImageFormat::Png, TransformParams::default(), None);
+        assert_ne!(webp[header::ETAG], png[header::ETAG]);
+        assert_ne!(png[header::ETAG], original[header::ETAG]);
+    }
+
+    fn conditional(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn if_none_match_compares_etags() {
+        let modified = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let etag = "\"abc\"";
+        for (value, expected) in [
+            ("\"abc\"", true),
+            ("W/\"abc\"", true),
+            ("\"xyz\", \"abc\"", true),
+            ("*", true),
+            ("\"xyz\"", false),
+        ] {
+            let headers = conditional(header::IF_NONE_MATCH, value);
+            assert_eq!(
+                is_not_modified(&headers, etag, modified),
+                expected,
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn if_modified_since_compares_dates() {
+        let modified = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let headers = |value| conditional(header::IF_MODIFIED_SINCE, value);
+        assert!(is_not_modified(
+            &headers("Tue, 14 Nov 2023 22:13:20 GMT"),
+            "\"abc\"",
+            modified
+        ));
+        assert!(!is_not_modified(
+            &headers("Tue, 14 Nov 2023 22:13:19 GMT"),
+            "\"abc\"",
+            modified
+        ));
+        assert!(!is_not_modified(&headers("yesterday"), "\"abc\"", modified));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let modified = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut headers = conditional(header::IF_NONE_MATCH, "\"xyz\"");
         headers.insert(
-            axum::http::header::CONTENT_LENGTH,
-            HeaderValue::from_str(&cached_data.len().to_string()).unwrap(),
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Tue, 14 Nov 2023 22:13:20 GMT"),
         );
-        return Ok((headers, cached_data));
+        assert!(!is_not_modified(&headers, "\"abc\"", modified));
     }
 
-    // If not in cache, get from storage
-    let (object_name, bucket) = find_image_with_extension(&state.bucket, &file_id)
-        .await
-        .map_err(StatusCode::from)?;
-
-    let object = bucket.get_object(&object_name).await.map_err(|e| {
-        error!("Failed to get object: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    #[test]
+    fn not_modified_responses_have_no_body() {
+        let image = CachedImage {
+            content_type: "image/webp".to_string(),
+            etag: "\"abc\"".to_string(),
+            last_modified: Utc::now(),
+            data: Bytes::from_static(b"webp"),
+        };
+        let request = conditional(header::IF_NONE_MATCH, &image.etag);
 
-    let data = object.bytes();
+        let (status, headers, body) = respond(image, &request, false, "public").unwrap();
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+        assert!(!headers.contains_key(header::CONTENT_TYPE));
+        assert_eq!(headers[header::CACHE_CONTROL], "public");
+    }
 
-    // Process image if any parameters are specified
-    let (processed_data, content_type) = if params.width.is_some()
-        || params.height.is_some()
-        || params.quality.is_some()
-        || params.format.is_some()
-    {
-        process_image(data, &params).map_err(|e| {
-            error!("Image processing error: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?
-    } else {
-        // If no processing needed, determine content type from original
-        let content_type = match object_name.split('.').last().unwrap_or("") {
-            "jpg" | "jpeg" => "image/jpeg",
-            "png" => "image/png",
-            "gif" => "image/gif",
-            "webp" => "image/webp",
-            "svg" => "image/svg+xml",
-            _ => "application/octet-stream",
+    #[test]
+    fn presets_and_signed_urls_are_not_immutable() {
+        let config = CacheControlConfig {
+            immutable_max_age: 100,
+            max_age: 10,
         };
-        (data.to_vec(), content_type.to_string())
-    };
+        assert_eq!(
+            cache_control(&config, false, false),
+            "public, max-age=100, immutable"
+        );
+        assert_eq!(cache_control(&config, false, true), "public, max-age=10");
+        assert_eq!(
+            cache_control(&config, true, false),
+            "private, max-age=100, immutable"
+        );
+    }
 
-    // Cache the processed result
-    if let Err(e) = set_in_cache(&state.redis, &cache_key, &processed_data, 3600).await {
-        error!("Failed to cache image: {}", e);
+    #[test]
+    fn if_range_needs_the_current_validator() {
+        let modified = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        assert!(is_range_current(&HeaderMap::new(), "\"abc\"", modified));
+        let current = conditional(header::IF_RANGE, "\"abc\"");
+        assert!(is_range_current(&current, "\"abc\"", modified));
+        let weak = conditional(header::IF_RANGE, "W/\"abc\"");
+        assert!(!is_range_current(&weak, "\"abc\"", modified));
+        let date = conditional(header::IF_RANGE, "Tue, 14 Nov 2023 22:13:20 GMT");
+        assert!(is_range_current(&date, "\"abc\"", modified));
+        let older = conditional(header::IF_RANGE, "Tue, 14 Nov 2023 22:13:19 GMT");
+        assert!(!is_range_current(&older, "\"abc\"", modified));
     }
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        axum::http::header::CONTENT_TYPE,
-        HeaderValue::from_str(&content_type).map_err(|e| {
-            error!("Invalid content-type header value: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?,
-    );
-    headers.insert(
-        axum::http::header::CONTENT_LENGTH,
-        HeaderValue::from_str(&processed_data.len().to_string()).map_err(|e| {
-            error!("Failed to create content-length header: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?,
-    );
+    #[test]
+    fn eager_variants_are_listed_by_preset() {
+        let config = TransformConfig {
+            require_signature: false,
+            max_concurrent: None,
+            max_queued: 1,
+            retry_after: 1,
+            presets: HashMap::from([("thumb".to_string(), with_format("webp"))]),
+            eager: vec!["thumb".to_string(), "missing".to_string()],
+            max_eager: 1,
+        };
+        let signer = UrlSigner::new(&SigningConfig {
+            key: Some("secret".to_string()),
+            default_ttl: 60,
+            max_ttl: 60,
+        });
 
-    Ok((headers, processed_data.into()))
+        let variants = eager_variants(&config, &signer, "file", db::Visibility::Unlisted);
+        assert_eq!(variants.len(), 1);
+        assert_eq!(variants[0].preset, "thumb");
+        assert_eq!(variants[0].url, "/images/file?preset=thumb");
+        assert!(variants[0].expires_at.is_none());
+
+        // Private images are only served through signed URLs
+        let variants = eager_variants(&config, &signer, "file", db::Visibility::Private);
+        assert!(variants[0].expires_at.is_some());
+        let (_, query) = variants[0].url.split_once('?').unwrap();
+        let params = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect::<Vec<_>>();
+        assert!(params.contains(&("preset".to_string(), "thumb".to_string())));
+        assert!(signer.verify("file", &params).is_ok());
+    }
 }
@@ -0,0 +1,20 @@
+use crate::auth::{Authorized, ReadScope};
+use crate::quota;
+use crate::state::AppState;
+use axum::{extract::State, http::StatusCode, response::Json};
+use common::usage::UsageResponse;
+use tracing::error;
+
+pub async fn usage_handler(
+    State(state): State<AppState>,
+    user: Authorized<ReadScope>,
+) -> Result<Json<UsageResponse>, StatusCode> {
+    let usage = quota::get_usage(&state.db, &state.limits, &user.user_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to get usage: {}", e);
+            StatusCode::from(e)
+        })?;
+
+    Ok(Json(usage))
+}
@@ -1,73 +1,65 @@
-use crate::db::{image, user};
+use crate::auth::{Authorized, ReadScope};
+use crate::db::{image, user, Visibility};
+use crate::handlers::get_image::eager_variants;
 use crate::state::AppState;
 use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
+    extract::{Path, State},
+    http::StatusCode,
     response::Json,
 };
 use common::list::{ImageInfo, ListImagesResponse};
 use tracing::error;
 
-#[derive(Debug)]
-pub enum ListImagesError {
-    InvalidCredentials,
-    DatabaseError(String),
-}
-
-impl From<ListImagesError> for StatusCode {
-    fn from(error: ListImagesError) -> StatusCode {
-        match error {
-            ListImagesError::InvalidCredentials => StatusCode::UNAUTHORIZED,
-            ListImagesError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        }
-    }
-}
-
-async fn validate_user(
-    db: &AppState,
-    username: &str,
-    key: &str,
-) -> Result<String, ListImagesError> {
-    let user = db
+pub async fn list_images_handler(
+    State(state): State<AppState>,
+    user: Authorized<ReadScope>,
+) -> Result<Json<ListImagesResponse>, StatusCode> {
+    // Get all images for the user
+    let images = state
         .db
-        .user()
-        .find_first(vec![user::username::equals(username.to_string())])
+        .image()
+        .find_many(vec![image::user_id::equals(user.user_id.clone())])
         .exec()
         .await
-        .map_err(|e| ListImagesError::DatabaseError(e.to_string()))?;
+        .map_err(|e| {
+            error!("Database error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
-    match user {
-        Some(user) if user.key == key => Ok(user.id),
-        _ => Err(ListImagesError::InvalidCredentials),
-    }
+    // Transform the images into the response format
+    let images = images
+        .into_iter()
+        .map(|img| image_info(&state, img))
+        .collect();
+
+    Ok(Json(ListImagesResponse { images }))
 }
 
-pub async fn list_images_handler(
+/// Lists a user's public images to anyone. Unlisted images are served the same way, but
+/// only to those who know their file ID, so they're left out.
+pub async fn public_images_handler(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    Path(username): Path<String>,
 ) -> Result<Json<ListImagesResponse>, StatusCode> {
-    // Extract username and key from headers
-    let username = headers
-        .get("X-Username")
-        .and_then(|h| h.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-
-    let key = headers
-        .get("X-Access-Key")
-        .and_then(|h| h.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-
-    // Validate user and get user ID
-    let user_id = match validate_user(&state, username, key).await {
-        Ok(id) => id,
-        Err(e) => return Err(StatusCode::from(e)),
-    };
+    let owner = state
+        .db
+        .user()
+        .find_unique(user::username::equals(username))
+        .exec()
+        .await
+        .map_err(|e| {
+            error!("Database error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    // Get all images for the user
     let images = state
         .db
         .image()
-        .find_many(vec![image::user_id::equals(user_id)])
+        .find_many(vec![
+            image::user_id::equals(owner.id),
+            image::visibility::equals(Visibility::Public),
+        ])
         .exec()
         .await
         .map_err(|e| {
@@ -75,15 +67,25 @@ pub async fn list_images_handler(
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
-    // Transform the images into the response format
     let images = images
         .into_iter()
-        .map(|img| ImageInfo {
-            file_id: img.file_id.to_string(),
-            url: format!("/images/{}", img.file_id),
-            created_at: img.created_at.into(),
-        })
+        .map(|img| image_info(&state, img))
         .collect();
 
     Ok(Json(ListImagesResponse { images }))
 }
+
+fn image_info(state: &AppState, img: image::Data) -> ImageInfo {
+    ImageInfo {
+        file_id: img.file_id.to_string(),
+        url: format!("/images/{}", img.file_id),
+        visibility: img.visibility.into(),
+        created_at: img.created_at.into(),
+        variants: eager_variants(
+            &state.transforms,
+            &state.signer,
+            &img.file_id,
+            img.visibility,
+        ),
+    }
+}
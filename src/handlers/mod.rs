@@ -1,17 +1,27 @@
+use std::time::Duration;
+
 use axum::{
     routing::{delete, get, post},
     Router,
 };
+use tower_http::timeout::TimeoutLayer;
 
+pub mod admin;
+pub mod api_keys;
 pub mod delete_image;
 pub mod get_image;
+pub mod get_usage;
 pub mod health_check;
 pub mod list_images;
 pub mod register_user;
+pub mod signed_url;
 pub mod upload_image;
 use crate::state::AppState;
 
-pub fn create_router() -> Router<AppState> {
+/// How long any request other than an upload may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn create_router(upload_timeout: Duration) -> Router<AppState> {
     let api_router = Router::new()
         .route("/health", get(health_check::health_handler))
         .route("/register", post(register_user::register_user_handler))
@@ -19,10 +29,44 @@ pub fn create_router() -> Router<AppState> {
             "/delete/:file_id",
             delete(delete_image::delete_image_handler),
         )
-        .route("/upload", post(upload_image::upload_image_handler))
-        .route("/list", get(list_images::list_images_handler));
+        .route("/list", get(list_images::list_images_handler))
+        .route(
+            "/users/:username/images",
+            get(list_images::public_images_handler),
+        )
+        .route("/usage", get(get_usage::usage_handler))
+        .route(
+            "/keys",
+            get(api_keys::list_keys_handler).post(api_keys::create_key_handler),
+        )
+        .route("/keys/:key_id", delete(api_keys::revoke_key_handler))
+        .route("/images/:file_id/url", post(signed_url::signed_url_handler))
+        .route("/admin/transforms", get(admin::transform_stats_handler))
+        .route(
+            "/admin/cache",
+            get(admin::cache_stats_handler).delete(admin::flush_cache_handler),
+        )
+        .route(
+            "/admin/cache/files/:file_id",
+            delete(admin::flush_file_cache_handler),
+        )
+        .route(
+            "/admin/cache/users/:username",
+            delete(admin::flush_user_cache_handler),
+        )
+        .layer(TimeoutLayer::new(REQUEST_TIMEOUT))
+        // Uploads stream large bodies, so they get their own, longer timeout
+        .route(
+            "/upload",
+            post(upload_image::upload_image_handler).layer(TimeoutLayer::new(upload_timeout)),
+        );
 
-    let images_router = Router::new().route("/:file_id", get(get_image::get_image_handler));
+    let images_router = Router::new()
+        .route(
+            "/:file_id",
+            get(get_image::get_image_handler).head(get_image::head_image_handler),
+        )
+        .layer(TimeoutLayer::new(REQUEST_TIMEOUT));
 
     Router::new()
         .nest("/api", api_router)
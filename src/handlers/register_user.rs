@@ -1,11 +1,14 @@
+use crate::auth;
 use crate::db::{user, PrismaClient};
 use crate::state::AppState;
 use axum::{
     extract::{Json, State},
     http::StatusCode,
 };
-use common::register::{RegisterUserRequest, RegisterUserResponse};
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use common::{
+    keys::Scope,
+    register::{RegisterUserRequest, RegisterUserResponse},
+};
 use tracing::error;
 
 #[derive(Debug)]
@@ -36,15 +39,6 @@ impl std::fmt::Display for RegistrationError {
     }
 }
 
-fn generate_key(username: &str) -> String {
-    let random_part: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(36)
-        .map(char::from)
-        .collect();
-    format!("{}_{}", username, random_part)
-}
-
 async fn check_username_exists(
     db: &PrismaClient,
     username: &str,
@@ -59,13 +53,32 @@ async fn check_username_exists(
 async fn create_user(
     db: &PrismaClient,
     username: &str,
-    access_key: &str,
-) -> Result<user::Data, RegistrationError> {
-    db.user()
-        .create(username.to_string(), access_key.to_string(), vec![])
+) -> Result<(user::Data, String), RegistrationError> {
+    let user = db
+        .user()
+        .create(username.to_string(), vec![])
         .exec()
         .await
-        .map_err(|e| RegistrationError::DatabaseError(e.to_string()))
+        .map_err(|e| RegistrationError::DatabaseError(e.to_string()))?;
+
+    // Every user starts out with one key, more can be created with it. Without one the
+    // account would be unusable and its username taken, so drop it again
+    let key = match auth::create_key(db, &user.id, "default", &Scope::ALL, None).await {
+        Ok((key, _)) => key,
+        Err(e) => {
+            if let Err(e) = db
+                .user()
+                .delete(user::id::equals(user.id.clone()))
+                .exec()
+                .await
+            {
+                error!("Failed to remove user {} without a key: {}", user.id, e);
+            }
+            return Err(RegistrationError::DatabaseError(e.to_string()));
+        }
+    };
+
+    Ok((user, key))
 }
 
 pub async fn register_user_handler(
@@ -91,12 +104,9 @@ pub async fn register_user_handler(
         }
     }
 
-    // Generate a key
-    let key = generate_key(&payload.username);
-
-    // Create user
-    match create_user(&state.db, &payload.username.clone(), &key).await {
-        Ok(_user) => Ok(Json(RegisterUserResponse {
+    // Create user along with their first key
+    match create_user(&state.db, &payload.username).await {
+        Ok((_user, key)) => Ok(Json(RegisterUserResponse {
             username: payload.username,
             key,
         })),
@@ -0,0 +1,97 @@
+use crate::auth::{AuthError, Authorized, ReadScope};
+use crate::db::image;
+use crate::signing::{EXPIRES_PARAM, SIGNATURE_PARAM};
+use crate::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Utc};
+use common::signed_url::{SignedUrlRequest, SignedUrlResponse};
+use tracing::error;
+
+#[derive(Debug)]
+pub enum SignedUrlError {
+    ImageNotFound,
+    NotAuthorized,
+    InvalidParams,
+    DatabaseError(String),
+}
+
+impl From<SignedUrlError> for StatusCode {
+    fn from(error: SignedUrlError) -> StatusCode {
+        match error {
+            SignedUrlError::ImageNotFound => StatusCode::NOT_FOUND,
+            SignedUrlError::NotAuthorized => StatusCode::FORBIDDEN,
+            SignedUrlError::InvalidParams => StatusCode::BAD_REQUEST,
+            SignedUrlError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for SignedUrlError {
+    fn into_response(self) -> Response {
+        match self {
+            SignedUrlError::NotAuthorized => AuthError::Forbidden.into_response(),
+            SignedUrlError::DatabaseError(ref e) => {
+                error!("Database error: {}", e);
+                StatusCode::from(self).into_response()
+            }
+            _ => StatusCode::from(self).into_response(),
+        }
+    }
+}
+
+async fn verify_image_ownership(
+    state: &AppState,
+    file_id: &str,
+    user_id: &str,
+) -> Result<(), SignedUrlError> {
+    let image = state
+        .db
+        .image()
+        .find_unique(image::file_id::equals(file_id.to_string()))
+        .exec()
+        .await
+        .map_err(|e| SignedUrlError::DatabaseError(e.to_string()))?;
+
+    match image {
+        Some(image) if image.user_id == user_id => Ok(()),
+        Some(_) => Err(SignedUrlError::NotAuthorized),
+        None => Err(SignedUrlError::ImageNotFound),
+    }
+}
+
+pub async fn signed_url_handler(
+    State(state): State<AppState>,
+    user: Authorized<ReadScope>,
+    Path(file_id): Path<String>,
+    Json(payload): Json<SignedUrlRequest>,
+) -> Result<Json<SignedUrlResponse>, SignedUrlError> {
+    verify_image_ownership(&state, &file_id, &user.user_id).await?;
+
+    // These are set by the signer itself
+    if payload.params.contains_key(EXPIRES_PARAM) || payload.params.contains_key(SIGNATURE_PARAM) {
+        return Err(SignedUrlError::InvalidParams);
+    }
+
+    let ttl = payload
+        .expires_in
+        .unwrap_or(state.signer.default_ttl)
+        .min(state.signer.max_ttl);
+    let expires_at = Utc::now().timestamp() + i64::try_from(ttl).unwrap_or(i64::MAX / 2);
+    let expires_at =
+        DateTime::from_timestamp(expires_at, 0).ok_or(SignedUrlError::InvalidParams)?;
+
+    let query = state.signer.signed_query(
+        &file_id,
+        payload.params.into_iter().collect(),
+        expires_at.timestamp(),
+    );
+
+    Ok(Json(SignedUrlResponse {
+        url: format!("/images/{}?{}", file_id, query),
+        expires_at,
+    }))
+}
@@ -1,138 +1,462 @@
-use crate::db::user;
+use crate::auth::{Authorized, UploadScope};
+use crate::db::{self, image, user};
+use crate::handlers::get_image::{eager_variants, render_eager_variants};
+use crate::metered::MeteredReader;
+use crate::objects::{self, ObjectError};
+use crate::quota::{self, QuotaError};
 use crate::state::AppState;
+use crate::validation::{sniff_image, SniffedImage, ValidationError};
 use axum::{
-    extract::{Multipart, State},
-    http::{HeaderMap, StatusCode},
+    extract::{multipart::Field, Multipart, Query, State},
+    http::StatusCode,
     response::Json,
 };
-use common::upload::UploadImageResponse;
+use bytes::{Bytes, BytesMut};
+use common::{
+    config::LimitsConfig,
+    upload::{
+        UploadBatchResponse, UploadImageResponse, UploadOptions, UploadOutcome, UploadResult,
+        Visibility,
+    },
+};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use std::io;
+use tokio_util::io::StreamReader;
 use tracing::error;
 use uuid::Uuid;
 
+/// How much of an upload is read up front to detect its format.
+const HEADER_PREFIX_SIZE: usize = 16 * 1024;
+
+/// Upper bound for the header read, for formats with large metadata before the dimensions.
+const MAX_HEADER_SIZE: usize = 1024 * 1024;
+
 #[derive(Debug)]
 pub enum UploadError {
-    InvalidCredentials,
     DatabaseError(String),
     InvalidFile,
     StorageError,
+    Rejected(ValidationError),
+    Quota(QuotaError),
+    TooManyFiles,
 }
 
 impl From<UploadError> for StatusCode {
     fn from(error: UploadError) -> StatusCode {
         match error {
-            UploadError::InvalidCredentials => StatusCode::UNAUTHORIZED,
             UploadError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             UploadError::InvalidFile => StatusCode::BAD_REQUEST,
             UploadError::StorageError => StatusCode::INTERNAL_SERVER_ERROR,
+            UploadError::Rejected(e) => StatusCode::from(e),
+            UploadError::Quota(e) => StatusCode::from(e),
+            UploadError::TooManyFiles => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::DatabaseError(_) => write!(f, "Database error"),
+            UploadError::InvalidFile => write!(f, "Invalid file"),
+            UploadError::StorageError => write!(f, "Storage error"),
+            UploadError::Rejected(e) => write!(f, "{}", e),
+            UploadError::Quota(e) => write!(f, "{}", e),
+            UploadError::TooManyFiles => write!(f, "Too many files in one request"),
+        }
+    }
+}
+
+impl From<ValidationError> for UploadError {
+    fn from(error: ValidationError) -> UploadError {
+        UploadError::Rejected(error)
+    }
+}
+
+impl From<ObjectError> for UploadError {
+    fn from(error: ObjectError) -> UploadError {
+        match error {
+            ObjectError::DatabaseError(err) => UploadError::DatabaseError(err),
+            ObjectError::StorageError(_) => UploadError::StorageError,
         }
     }
 }
 
-async fn validate_user(db: &AppState, username: &str, key: &str) -> Result<String, UploadError> {
-    let user = db
+impl From<QuotaError> for UploadError {
+    fn from(error: QuotaError) -> UploadError {
+        UploadError::Quota(error)
+    }
+}
+
+async fn release_quota(state: &AppState, user_id: &str, size: u64) {
+    if let Err(e) = quota::release(&state.db, user_id, size).await {
+        error!("Failed to release quota for user {}: {}", user_id, e);
+    }
+}
+
+async fn delete_object(state: &AppState, object_name: &str) {
+    if let Err(e) = state.storage.delete(object_name).await {
+        error!("Failed to delete object {}: {}", object_name, e);
+    }
+}
+
+async fn release_object(state: &AppState, key: &str) {
+    if let Err(e) = objects::release(&state.db, state.storage.as_ref(), key).await {
+        error!("Failed to release object {}: {}", key, e);
+    }
+}
+
+/// Drops an upload that won't be kept: deletes its object and gives back its quota.
+async fn discard_upload(state: &AppState, user_id: &str, object_name: &str, reserved: u64) {
+    delete_object(state, object_name).await;
+    release_quota(state, user_id, reserved).await;
+}
+
+/// Reads just enough of the field to detect the format and dimensions of the image.
+///
+/// Starts with a small prefix and keeps doubling it while the header is cut off, since
+/// some formats keep their dimensions behind metadata of arbitrary size.
+async fn read_header(
+    field: &mut Field<'_>,
+    limits: &LimitsConfig,
+) -> Result<(Bytes, SniffedImage), ValidationError> {
+    let mut header = BytesMut::new();
+    let mut wanted = HEADER_PREFIX_SIZE;
+
+    loop {
+        let mut finished = false;
+        while header.len() < wanted {
+            match field.chunk().await {
+                Ok(Some(chunk)) => header.extend_from_slice(&chunk),
+                Ok(None) => {
+                    finished = true;
+                    break;
+                }
+                Err(e) => return Err(ValidationError::Undecodable(e.to_string())),
+            }
+        }
+
+        if header.len() as u64 > limits.max_file_size {
+            return Err(ValidationError::FileTooLarge);
+        }
+
+        match sniff_image(&header, limits) {
+            Err(ValidationError::Undecodable(_)) if !finished && wanted < MAX_HEADER_SIZE => {
+                wanted *= 2;
+            }
+            result => return result.map(|sniffed| (header.freeze(), sniffed)),
+        }
+    }
+}
+
+/// Streams a single file field into storage and records it for the user.
+async fn store_file(
+    state: &AppState,
+    user_id: &str,
+    mut field: Field<'_>,
+    file_name: Option<String>,
+    options: &UploadOptions,
+) -> Result<UploadImageResponse, UploadError> {
+    tracing::debug!("Client content type: {:?}", field.content_type());
+
+    // Detect the real format from the magic bytes and check the dimensions
+    // before anything is written to storage
+    let (header, sniffed) = read_header(&mut field, &state.limits).await?;
+
+    // Reserve room before anything is written, the upload is cut off where the reservation
+    // ends so a user can't store more than their quota allows
+    let reserved = quota::reserve(
+        &state.db,
+        &state.limits,
+        user_id,
+        state.limits.max_file_size,
+    )
+    .await?;
+
+    // Generate a unique file ID
+    let file_id = Uuid::new_v4().to_string();
+    let object_name = format!("{}.{}", file_id, sniffed.extension());
+
+    tracing::debug!("Uploading object: {}", object_name);
+
+    // Stream the already read header and the rest of the field into storage,
+    // counting and hashing the bytes as they pass through
+    let body = stream::iter([Ok(header)]).chain(field.map_err(io::Error::other));
+    let mut reader = MeteredReader::new(StreamReader::new(body), reserved);
+    let stored = state
+        .storage
+        .put_stream(&object_name, &mut reader, sniffed.mime_type())
+        .await;
+
+    if reader.exceeded_limit() || stored.is_err() {
+        // A failed write may still leave a partial object behind
+        discard_upload(state, user_id, &object_name, reserved).await;
+    }
+    if reader.exceeded_limit() {
+        return Err(if reserved < state.limits.max_file_size {
+            QuotaError::QuotaExceeded.into()
+        } else {
+            ValidationError::FileTooLarge.into()
+        });
+    }
+    if let Err(e) = stored {
+        error!("Storage error: {}", e);
+        return Err(UploadError::StorageError);
+    }
+
+    let (size, hash) = reader.finish();
+    tracing::debug!("Stored {} ({} bytes, sha256 {})", object_name, size, hash);
+
+    // Nothing was given back if settling failed, so drop the upload with the whole reservation
+    if let Err(e) = quota::settle(&state.db, user_id, reserved, size).await {
+        error!("Failed to settle quota for user {}: {}", user_id, e);
+        discard_upload(state, user_id, &object_name, reserved).await;
+        return Err(e.into());
+    }
+
+    let stored = StoredUpload {
+        file_id,
+        object_name,
+        size,
+        hash,
+    };
+    let recorded = record_image(state, user_id, &stored, &sniffed, file_name, options).await;
+    match recorded {
+        Ok(Recorded::New(image)) => {
+            let url = format!("/images/{}", stored.file_id);
+            let variants = eager_variants(
+                &state.transforms,
+                &state.signer,
+                &stored.file_id,
+                image.visibility,
+            );
+            if !variants.is_empty() {
+                tokio::spawn(render_eager_variants(state.clone(), image));
+            }
+            Ok(UploadImageResponse {
+                file_id: stored.file_id,
+                url,
+                variants,
+            })
+        }
+        Ok(Recorded::Duplicate(existing)) => {
+            tracing::debug!("Upload is a duplicate of {}", existing.file_id);
+            discard_upload(state, user_id, &stored.object_name, stored.size).await;
+            let url = format!("/images/{}", existing.file_id);
+            Ok(UploadImageResponse {
+                variants: eager_variants(
+                    &state.transforms,
+                    &state.signer,
+                    &existing.file_id,
+                    existing.visibility,
+                ),
+                file_id: existing.file_id,
+                url,
+            })
+        }
+        Err(e) => {
+            discard_upload(state, user_id, &stored.object_name, stored.size).await;
+            Err(e)
+        }
+    }
+}
+
+/// An upload written to storage, before it's recorded.
+struct StoredUpload {
+    file_id: String,
+    object_name: String,
+    size: u64,
+    hash: String,
+}
+
+/// What became of a stored upload.
+enum Recorded {
+    New(image::Data),
+    /// The user already has an image with the same bytes and visibility.
+    Duplicate(image::Data),
+}
+
+/// Whether an image the user already has can stand in for an upload of the same bytes.
+/// Only if it's served the same way, a private upload must not come back with a public URL.
+fn can_reuse(existing: db::Visibility, requested: Visibility) -> bool {
+    existing == requested.into()
+}
+
+/// Creates the image row for a stored upload, unless the user already has the same image.
+async fn record_image(
+    state: &AppState,
+    user_id: &str,
+    stored: &StoredUpload,
+    sniffed: &SniffedImage,
+    file_name: Option<String>,
+    options: &UploadOptions,
+) -> Result<Recorded, UploadError> {
+    // Identical bytes from the same user are the same image, if they're served the same way
+    let existing = state
         .db
-        .user()
-        .find_first(vec![user::username::equals(username.to_string())])
+        .image()
+        .find_many(vec![
+            image::hash::equals(stored.hash.clone()),
+            image::user_id::equals(user_id.to_string()),
+        ])
         .exec()
         .await
-        .map_err(|e| UploadError::DatabaseError(e.to_string()))?;
+        .map_err(|e| UploadError::DatabaseError(e.to_string()))?
+        .into_iter()
+        .find(|image| can_reuse(image.visibility, options.visibility));
+    if let Some(existing) = existing {
+        return Ok(Recorded::Duplicate(existing));
+    }
+
+    // Otherwise identical bytes share their stored object, also with other users', as long as
+    // it's still referenced
+    let mut object_key = stored.object_name.clone();
+    if let Some(shared) = find_by_hash(state, &stored.hash).await? {
+        if objects::acquire(&state.db, &shared.object_key).await? {
+            object_key = shared.object_key;
+        }
+    }
+    let shared = object_key != stored.object_name;
+    if !shared {
+        objects::track(&state.db, &object_key).await?;
+    }
+
+    // Create image record in database
+    let created = state
+        .db
+        .image()
+        .create(
+            stored.file_id.clone(),
+            object_key.clone(),
+            sniffed.mime_type().to_string(),
+            stored.size as i64,
+            sniffed.width as i32,
+            sniffed.height as i32,
+            stored.hash.clone(),
+            user::id::equals(user_id.to_string()),
+            vec![
+                image::original_name::set(file_name),
+                image::visibility::set(options.visibility.into()),
+            ],
+        )
+        .exec()
+        .await;
+    let image = match created {
+        Ok(image) => image,
+        Err(e) => {
+            error!("Failed to create image record: {}", e);
+            release_object(state, &object_key).await;
+            return Err(UploadError::DatabaseError(e.to_string()));
+        }
+    };
 
-    match user {
-        Some(user) if user.key == key => Ok(user.id),
-        _ => Err(UploadError::InvalidCredentials),
+    // The shared object is referenced by the new image now, so the upload's copy can go
+    if shared {
+        delete_object(state, &stored.object_name).await;
     }
+    Ok(Recorded::New(image))
+}
+
+/// Finds an image with the given content hash.
+async fn find_by_hash(state: &AppState, hash: &str) -> Result<Option<image::Data>, UploadError> {
+    state
+        .db
+        .image()
+        .find_first(vec![image::hash::equals(hash.to_string())])
+        .exec()
+        .await
+        .map_err(|e| UploadError::DatabaseError(e.to_string()))
 }
 
 pub async fn upload_image_handler(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    user: Authorized<UploadScope>,
+    Query(options): Query<UploadOptions>,
     mut multipart: Multipart,
-) -> Result<Json<UploadImageResponse>, StatusCode> {
-    // Extract username and key from headers
-    let username = headers
-        .get("X-Username")
-        .and_then(|h| h.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-
-    let key = headers
-        .get("X-Access-Key")
-        .and_then(|h| h.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-
-    // Validate user and get user ID
-    let user_id = match validate_user(&state, username, key).await {
-        Ok(id) => id,
-        Err(e) => return Err(StatusCode::from(e)),
-    };
+) -> Result<(StatusCode, Json<UploadBatchResponse>), StatusCode> {
+    // Handle every file in the form, one after another
+    let mut files = Vec::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            // Files handled so far are stored, so report them rather than failing outright
+            Err(e) if !files.is_empty() => {
+                error!("Failed to read multipart form: {}", e);
+                break;
+            }
+            Err(_) => return Err(StatusCode::BAD_REQUEST),
+        };
 
-    // Handle file upload
-    if let Some(field) = multipart
-        .next_field()
-        .await
-        .map_err(|_| StatusCode::BAD_REQUEST)?
-    {
-        // Add debug logging for field name
         tracing::debug!("Received field name: {:?}", field.name());
 
-        let file_name = field
-            .file_name()
-            .ok_or(StatusCode::BAD_REQUEST)?
-            .to_string();
+        // Only fields carrying a file name are files, anything else is a plain form value.
+        // The name is only kept for reference, never used to pick the format.
+        let Some(file_name) = field.file_name().map(str::to_string) else {
+            continue;
+        };
 
-        let content_type = field
-            .content_type()
-            .ok_or(StatusCode::BAD_REQUEST)?
-            .to_string();
+        let result = if files.len() >= state.limits.max_files_per_request {
+            Err(UploadError::TooManyFiles)
+        } else {
+            store_file(
+                &state,
+                &user.user_id,
+                field,
+                Some(file_name.clone()),
+                &options,
+            )
+            .await
+        };
 
-        tracing::debug!("Content type: {}", content_type);
+        let outcome = match result {
+            Ok(upload) => UploadOutcome::Uploaded(upload),
+            Err(e) => {
+                error!("Rejected upload {:?}: {}", file_name, e);
+                let error = e.to_string();
+                UploadOutcome::Failed {
+                    code: StatusCode::from(e).as_u16(),
+                    error,
+                }
+            }
+        };
 
-        // More lenient content type check
-        if !content_type.starts_with("image/") && !content_type.contains("octet-stream") {
-            tracing::error!("Invalid content type: {}", content_type);
-            return Err(StatusCode::BAD_REQUEST);
+        files.push(UploadResult {
+            file_name: Some(file_name),
+            outcome,
+        });
+    }
+
+    if files.is_empty() {
+        error!("No file field found in multipart form");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // With nothing stored, the request as a whole failed like its first file did
+    let all_failed = files
+        .iter()
+        .all(|file| matches!(file.outcome, UploadOutcome::Failed { .. }));
+    let status = match &files[0].outcome {
+        UploadOutcome::Failed { code, .. } if all_failed => {
+            StatusCode::from_u16(*code).unwrap_or(StatusCode::BAD_REQUEST)
         }
+        _ => StatusCode::OK,
+    };
 
-        let data = field
-            .bytes()
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((status, Json(UploadBatchResponse { files })))
+}
 
-        // Generate a unique file ID
-        let file_id = Uuid::new_v4().to_string();
-        let extension = file_name.split('.').last().unwrap_or("jpg").to_lowercase();
-        let object_name = format!("{}.{}", file_id, extension);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        tracing::debug!("Uploading object: {}", object_name);
+    #[test]
+    fn duplicates_are_reused_only_with_the_same_visibility() {
+        assert!(can_reuse(db::Visibility::Public, Visibility::Public));
+        assert!(can_reuse(db::Visibility::Private, Visibility::Private));
 
-        // Upload to storage
-        state
-            .bucket
-            .put_object(&object_name, &data)
-            .await
-            .map_err(|e| {
-                tracing::error!("Storage error: {}", e);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?;
-
-        // Create image record in database
-        match state
-            .db
-            .image()
-            .create(file_id.clone(), user::id::equals(user_id), vec![])
-            .exec()
-            .await
-        {
-            Ok(_) => {
-                let url = format!("/images/{}", file_id);
-                Ok(Json(UploadImageResponse { file_id, url }))
-            }
-            Err(e) => {
-                error!("Failed to create image record: {}", e);
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
-            }
-        }
-    } else {
-        tracing::error!("No file field found in multipart form");
-        Err(StatusCode::BAD_REQUEST)
+        // Re-uploading a public image as private must not hand back the public one
+        assert!(!can_reuse(db::Visibility::Public, Visibility::Private));
+        assert!(!can_reuse(db::Visibility::Unlisted, Visibility::Public));
     }
 }
@@ -1,23 +1,21 @@
 use crate::handlers::create_router;
 use crate::layers::logger::LoggingMiddleware;
+use crate::signing::UrlSigner;
 use crate::state::AppState;
+use crate::transform::TransformPool;
+use axum::extract::DefaultBodyLimit;
 use color_eyre::eyre;
 use color_eyre::eyre::WrapErr;
 use common::{config::AppConfig, Config};
 use dotenvy::dotenv;
-use eyre::{Report, Result};
+use eyre::{bail, Report, Result};
 use fred::{
     prelude::ClientLike,
     types::{Builder, ReconnectPolicy, RedisConfig},
 };
-use s3::{
-    creds::Credentials,
-    region::Region,
-    {Bucket, BucketConfiguration},
-};
 use std::{process::ExitCode, sync::Arc, time::Duration};
 use time::macros::format_description;
-use tower_http::{compression::CompressionLayer, timeout::TimeoutLayer};
+use tower_http::compression::CompressionLayer;
 use tower_layer::layer_fn;
 use tracing::info;
 use tracing_error::ErrorLayer;
@@ -27,11 +25,24 @@ use tracing_subscriber::{
     EnvFilter,
 };
 
+mod auth;
+mod cache;
 #[allow(warnings, unused)]
 mod db;
+mod flight;
 mod handlers;
 mod layers;
+mod metered;
+mod objects;
+mod quota;
+mod range;
+mod signing;
 mod state;
+mod storage;
+mod transform;
+mod validation;
+mod variants;
+mod visibility;
 
 #[tokio::main]
 async fn main() -> Result<ExitCode, Report> {
@@ -73,45 +84,18 @@ async fn main() -> Result<ExitCode, Report> {
         .load()
         .context("Failed to load config")?;
 
+    if config.minio.is_set() {
+        bail!("The [minio] config section was renamed to [storage.s3], move its settings there");
+    }
+
     let prisma = db::new_client_with_url(&config.database_url)
         .await
         .with_context(|| "Failed to connect to database")?;
     info!("Connected to database");
 
-    let region = Region::Custom {
-        region: "eu-central-1".to_owned(),
-        endpoint: config.minio.endpoint,
-    };
-    let credentials = Credentials::new(
-        Some(&config.minio.access_key),
-        Some(&config.minio.secret_key),
-        None,
-        None,
-        None,
-    )?;
-
-    let mut bucket = Bucket::new(
-        &config.minio.bucket_name,
-        region.clone(),
-        credentials.clone(),
-    )?
-    .with_path_style();
-
-    let bkt_exists = bucket.exists().await?;
-
-    // Create bucket if it doesn't exist
-    if !bkt_exists {
-        bucket = Bucket::create_with_path_style(
-            &config.minio.bucket_name,
-            region,
-            credentials,
-            BucketConfiguration::default(),
-        )
+    let storage = storage::from_config(config.storage)
         .await
-        .with_context(|| format!("Failed to create bucket {}", config.minio.bucket_name))?
-        .bucket;
-    }
-    info!("Connected to Minio");
+        .context("Failed to set up storage")?;
 
     let redis_config =
         RedisConfig::from_url(&config.redis.url).expect("Failed to create redis config from url");
@@ -128,14 +112,28 @@ async fn main() -> Result<ExitCode, Report> {
     info!("Connected to Redis");
 
     let state = AppState {
-        bucket,
+        storage,
         db: Arc::new(prisma),
         admin_key: config.admin_key,
         redis: redis_pool,
+        limits: Arc::new(config.limits),
+        signer: Arc::new(UrlSigner::new(&config.signing)),
+        transform_pool: Arc::new(TransformPool::new(&config.transforms)),
+        transforms: Arc::new(config.transforms),
+        cache_control: Arc::new(config.cache_control),
+        cache: Arc::new(config.cache),
+        cache_counters: Arc::default(),
+        renders: Arc::default(),
     };
 
-    let app = create_router()
-        .layer(TimeoutLayer::new(Duration::from_secs(10)))
+    // Leave some room for the multipart framing around the files themselves
+    let body_limit = (state.limits.max_file_size as usize)
+        .saturating_mul(state.limits.max_files_per_request)
+        .saturating_add(64 * 1024);
+
+    let upload_timeout = Duration::from_secs(state.limits.upload_timeout);
+    let app = create_router(upload_timeout)
+        .layer(DefaultBodyLimit::max(body_limit))
         .layer(CompressionLayer::new())
         .layer(layer_fn(LoggingMiddleware))
         .with_state(state);
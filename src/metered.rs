@@ -0,0 +1,60 @@
+//! Size accounting and hashing for streamed uploads.
+
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Wraps a reader to count and hash everything read through it, failing once
+/// more than `limit` bytes have been read.
+pub struct MeteredReader<R> {
+    inner: R,
+    hasher: Sha256,
+    bytes_read: u64,
+    limit: u64,
+}
+
+impl<R> MeteredReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes_read: 0,
+            limit,
+        }
+    }
+
+    /// Whether reading was aborted because the limit was exceeded.
+    pub fn exceeded_limit(&self) -> bool {
+        self.bytes_read > self.limit
+    }
+
+    /// Returns the number of bytes read and their hex-encoded SHA-256 hash.
+    pub fn finish(self) -> (u64, String) {
+        (self.bytes_read, format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MeteredReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        let read = &buf.filled()[before..];
+        this.bytes_read += read.len() as u64;
+        if this.bytes_read > this.limit {
+            return Poll::Ready(Err(io::Error::other("upload exceeds the size limit")));
+        }
+        this.hasher.update(read);
+
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,101 @@
+//! Reference counts of stored objects, which identical uploads share.
+//!
+//! Counts only change with conditional updates, so an object can't gain a reference once its
+//! last one is gone: an upload that would have shared it keeps its own copy instead. Objects
+//! stored before counts were kept have no row, and are never shared by new uploads.
+
+use crate::db::{image, stored_object, PrismaClient};
+use crate::storage::{Storage, StorageError};
+
+#[derive(Debug)]
+pub enum ObjectError {
+    DatabaseError(String),
+    StorageError(StorageError),
+}
+
+impl std::fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            ObjectError::StorageError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Starts counting references to a newly stored object, with one for the image storing it.
+pub async fn track(db: &PrismaClient, key: &str) -> Result<(), ObjectError> {
+    db.stored_object()
+        .create(key.to_string(), vec![])
+        .exec()
+        .await
+        .map_err(|e| ObjectError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+/// Adds a reference to an object for another image, if it's still referenced at all.
+/// Returns whether it was, the object is only kept around as long as it is.
+pub async fn acquire(db: &PrismaClient, key: &str) -> Result<bool, ObjectError> {
+    let updated = db
+        .stored_object()
+        .update_many(
+            vec![
+                stored_object::key::equals(key.to_string()),
+                stored_object::ref_count::gt(0),
+            ],
+            vec![stored_object::ref_count::increment(1)],
+        )
+        .exec()
+        .await
+        .map_err(|e| ObjectError::DatabaseError(e.to_string()))?;
+    Ok(updated > 0)
+}
+
+/// Drops a reference to an object, deleting it along with its last one. For objects from
+/// before counts were kept, the image must already be deleted.
+pub async fn release(
+    db: &PrismaClient,
+    storage: &dyn Storage,
+    key: &str,
+) -> Result<(), ObjectError> {
+    let updated = db
+        .stored_object()
+        .update_many(
+            vec![
+                stored_object::key::equals(key.to_string()),
+                stored_object::ref_count::gt(0),
+            ],
+            vec![stored_object::ref_count::decrement(1)],
+        )
+        .exec()
+        .await
+        .map_err(|e| ObjectError::DatabaseError(e.to_string()))?;
+
+    let unreferenced = if updated == 0 {
+        // Untracked objects can't gain references, so counting the images is enough
+        db.image()
+            .count(vec![image::object_key::equals(key.to_string())])
+            .exec()
+            .await
+            .map_err(|e| ObjectError::DatabaseError(e.to_string()))?
+            == 0
+    } else {
+        // Only one release removes the row, so only one deletes the object
+        db.stored_object()
+            .delete_many(vec![
+                stored_object::key::equals(key.to_string()),
+                stored_object::ref_count::lte(0),
+            ])
+            .exec()
+            .await
+            .map_err(|e| ObjectError::DatabaseError(e.to_string()))?
+            > 0
+    };
+
+    if unreferenced {
+        storage
+            .delete(key)
+            .await
+            .map_err(ObjectError::StorageError)?;
+    }
+    Ok(())
+}
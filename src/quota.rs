@@ -0,0 +1,169 @@
+//! Per-user storage quotas.
+//!
+//! Usage is tracked on the `User` row and reserved with a conditional update,
+//! so concurrent uploads can't push a user past their quota.
+
+use crate::db::{user, PrismaClient};
+use axum::http::StatusCode;
+use common::{config::LimitsConfig, usage::UsageResponse};
+
+#[derive(Debug)]
+pub enum QuotaError {
+    UserNotFound,
+    QuotaExceeded,
+    DatabaseError(String),
+}
+
+impl From<QuotaError> for StatusCode {
+    fn from(error: QuotaError) -> StatusCode {
+        match error {
+            QuotaError::UserNotFound => StatusCode::UNAUTHORIZED,
+            QuotaError::QuotaExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            QuotaError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaError::UserNotFound => write!(f, "User not found"),
+            QuotaError::QuotaExceeded => write!(f, "Storage quota exceeded"),
+            QuotaError::DatabaseError(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+/// The user's own quota if one is set, the configured default otherwise.
+fn effective_quota(user: &user::Data, config: &LimitsConfig) -> (Option<u64>, Option<u64>) {
+    let max_bytes = user
+        .quota_bytes
+        .map(|bytes| bytes.max(0) as u64)
+        .or(config.quota_bytes);
+    let max_images = user
+        .quota_images
+        .map(|images| images.max(0) as u64)
+        .or(config.quota_images);
+
+    (max_bytes, max_images)
+}
+
+async fn find_user(db: &PrismaClient, user_id: &str) -> Result<user::Data, QuotaError> {
+    db.user()
+        .find_unique(user::id::equals(user_id.to_string()))
+        .exec()
+        .await
+        .map_err(|e| QuotaError::DatabaseError(e.to_string()))?
+        .ok_or(QuotaError::UserNotFound)
+}
+
+/// Returns how much the user currently stores, and how much they may store.
+pub async fn get_usage(
+    db: &PrismaClient,
+    config: &LimitsConfig,
+    user_id: &str,
+) -> Result<UsageResponse, QuotaError> {
+    let user = find_user(db, user_id).await?;
+    let (max_bytes, max_images) = effective_quota(&user, config);
+
+    Ok(UsageResponse {
+        bytes_used: user.storage_used.max(0) as u64,
+        image_count: user.image_count.max(0) as u64,
+        max_bytes,
+        max_images,
+        max_file_size: config.max_file_size,
+    })
+}
+
+/// Reserves room for one image of at most `max_size` bytes, before its size is known.
+///
+/// Returns how many bytes were reserved, which is less than `max_size` if the quota has less
+/// room left. The upload must not be larger, and is settled with [`settle`] once it's stored.
+pub async fn reserve(
+    db: &PrismaClient,
+    config: &LimitsConfig,
+    user_id: &str,
+    max_size: u64,
+) -> Result<u64, QuotaError> {
+    let user = find_user(db, user_id).await?;
+    let (max_bytes, max_images) = effective_quota(&user, config);
+
+    let mut reserved = max_size;
+    let mut conditions = vec![user::id::equals(user_id.to_string())];
+    if let Some(max_bytes) = max_bytes {
+        let remaining = max_bytes.saturating_sub(user.storage_used.max(0) as u64);
+        if remaining == 0 {
+            return Err(QuotaError::QuotaExceeded);
+        }
+        reserved = reserved.min(remaining);
+        conditions.push(user::storage_used::lte(
+            i64::try_from(max_bytes - reserved).unwrap_or(i64::MAX),
+        ));
+    }
+    if let Some(max_images) = max_images {
+        conditions.push(user::image_count::lt(
+            i32::try_from(max_images).unwrap_or(i32::MAX),
+        ));
+    }
+
+    // Only matches if the user still has room, so the check and increment are atomic
+    let updated = db
+        .user()
+        .update_many(
+            conditions,
+            vec![
+                user::storage_used::increment(reserved as i64),
+                user::image_count::increment(1),
+            ],
+        )
+        .exec()
+        .await
+        .map_err(|e| QuotaError::DatabaseError(e.to_string()))?;
+
+    if updated == 0 {
+        return Err(QuotaError::QuotaExceeded);
+    }
+
+    Ok(reserved)
+}
+
+/// Gives back the part of a reservation an upload of `size` bytes didn't use.
+pub async fn settle(
+    db: &PrismaClient,
+    user_id: &str,
+    reserved: u64,
+    size: u64,
+) -> Result<(), QuotaError> {
+    let unused = reserved.saturating_sub(size);
+    if unused == 0 {
+        return Ok(());
+    }
+
+    db.user()
+        .update(
+            user::id::equals(user_id.to_string()),
+            vec![user::storage_used::decrement(unused as i64)],
+        )
+        .exec()
+        .await
+        .map_err(|e| QuotaError::DatabaseError(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Gives back space reserved with [`reserve`], after a failed upload or a delete.
+pub async fn release(db: &PrismaClient, user_id: &str, size: u64) -> Result<(), QuotaError> {
+    db.user()
+        .update(
+            user::id::equals(user_id.to_string()),
+            vec![
+                user::storage_used::decrement(size as i64),
+                user::image_count::decrement(1),
+            ],
+        )
+        .exec()
+        .await
+        .map_err(|e| QuotaError::DatabaseError(e.to_string()))?;
+
+    Ok(())
+}
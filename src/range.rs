@@ -0,0 +1,113 @@
+//! `Range` request headers, for serving parts of originals.
+
+/// An inclusive range of bytes within an object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// Value of the `Content-Range` header of a response with this range.
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+/// None of the requested bytes are within the object.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RangeNotSatisfiable;
+
+/// Parses a `Range` header for an object of `size` bytes. Headers that can't be served as a
+/// single range, such as malformed ones or ones with several ranges, give `None`, and the
+/// whole object is served as if there were no header.
+pub fn parse(header: &str, size: u64) -> Result<Option<ByteRange>, RangeNotSatisfiable> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+
+    let (first, last) = (first.trim(), last.trim());
+    let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+        // The last `suffix` bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => {
+            if suffix == 0 || size == 0 {
+                return Err(RangeNotSatisfiable);
+            }
+            ByteRange {
+                start: size.saturating_sub(suffix),
+                end: size - 1,
+            }
+        }
+        (Ok(start), Err(_)) if last.is_empty() => ByteRange {
+            start,
+            end: size.saturating_sub(1),
+        },
+        (Ok(start), Ok(end)) if start <= end => ByteRange {
+            start,
+            end: end.min(size.saturating_sub(1)),
+        },
+        _ => return Ok(None),
+    };
+
+    if range.start >= size {
+        return Err(RangeNotSatisfiable);
+    }
+    Ok(Some(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> Result<Option<ByteRange>, RangeNotSatisfiable> {
+        Ok(Some(ByteRange { start, end }))
+    }
+
+    #[test]
+    fn parses_bounded_ranges() {
+        assert_eq!(parse("bytes=0-99", 1000), range(0, 99));
+        assert_eq!(parse("bytes=500-500", 1000), range(500, 500));
+        assert_eq!(parse("bytes=900-2000", 1000), range(900, 999));
+    }
+
+    #[test]
+    fn parses_open_ranges() {
+        assert_eq!(parse("bytes=100-", 1000), range(100, 999));
+        assert_eq!(parse("bytes=-100", 1000), range(900, 999));
+        assert_eq!(parse("bytes=-2000", 1000), range(0, 999));
+    }
+
+    #[test]
+    fn rejects_ranges_outside_the_object() {
+        assert_eq!(parse("bytes=1000-", 1000), Err(RangeNotSatisfiable));
+        assert_eq!(parse("bytes=1000-1100", 1000), Err(RangeNotSatisfiable));
+        assert_eq!(parse("bytes=-0", 1000), Err(RangeNotSatisfiable));
+        assert_eq!(parse("bytes=0-", 0), Err(RangeNotSatisfiable));
+    }
+
+    #[test]
+    fn ignores_ranges_it_cannot_serve() {
+        for header in [
+            "items=0-99",
+            "bytes=0-9,20-29",
+            "bytes=99-0",
+            "bytes=a-b",
+            "bytes=-",
+            "bytes=5",
+        ] {
+            assert_eq!(parse(header, 1000), Ok(None), "{}", header);
+        }
+    }
+
+    #[test]
+    fn describes_content_range() {
+        let range = ByteRange { start: 0, end: 99 };
+        assert_eq!(range.content_range(1000), "bytes 0-99/1000");
+    }
+}
@@ -1,14 +1,28 @@
+use crate::cache::{CacheCounters, CachedImage};
 use crate::db::PrismaClient;
+use crate::flight::SingleFlight;
+use crate::handlers::get_image::GetImageError;
+use crate::signing::UrlSigner;
+use crate::storage::DynStorage;
+use crate::transform::TransformPool;
+use common::config::{CacheConfig, CacheControlConfig, LimitsConfig, TransformConfig};
 use fred::clients::RedisPool;
-use s3::Bucket;
 use std::sync::Arc;
 
 pub type Database = Arc<PrismaClient>;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub bucket: Bucket,
+    pub storage: DynStorage,
     pub db: Database,
     pub redis: RedisPool,
     pub admin_key: String,
+    pub limits: Arc<LimitsConfig>,
+    pub signer: Arc<UrlSigner>,
+    pub transforms: Arc<TransformConfig>,
+    pub transform_pool: Arc<TransformPool>,
+    pub cache_control: Arc<CacheControlConfig>,
+    pub cache: Arc<CacheConfig>,
+    pub cache_counters: Arc<CacheCounters>,
+    pub renders: Arc<SingleFlight<Result<CachedImage, GetImageError>>>,
 }
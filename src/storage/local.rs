@@ -0,0 +1,215 @@
+use super::{ByteStream, ObjectMeta, Storage, StorageError};
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::{Result, WrapErr};
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+/// Stores images as plain files below a root directory, for single-box deployments.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub async fn new(root: PathBuf) -> Result<Self> {
+        tokio::fs::create_dir_all(&root)
+            .await
+            .with_context(|| format!("Failed to create storage directory {}", root.display()))?;
+
+        Ok(Self { root })
+    }
+
+    /// Maps an object key to a path below the root, rejecting keys that would escape it.
+    fn path_for(&self, key: &str) -> Result<PathBuf, StorageError> {
+        let relative = Path::new(key);
+        let is_plain = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+
+        if key.is_empty() || !is_plain {
+            return Err(StorageError::InvalidKey(key.to_string()));
+        }
+
+        Ok(self.root.join(relative))
+    }
+}
+
+/// A hidden sibling of `path` to write to before moving the finished file into place.
+fn temp_path_for(path: &Path) -> PathBuf {
+    path.with_file_name(format!(".{}.tmp", Uuid::new_v4()))
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(error: std::io::Error) -> StorageError {
+        match error.kind() {
+            ErrorKind::NotFound => StorageError::NotFound,
+            _ => StorageError::Backend(error.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: &[u8], _content_type: &str) -> Result<(), StorageError> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Write to a temporary file first so readers never see a partial object
+        let tmp_path = temp_path_for(&path);
+        tokio::fs::write(&tmp_path, data).await?;
+        if let Err(e) = tokio::fs::rename(&tmp_path, &path).await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        _content_type: &str,
+    ) -> Result<u64, StorageError> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let tmp_path = temp_path_for(&path);
+        let written = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            let written = tokio::io::copy(reader, &mut file).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp_path, &path).await?;
+            Ok::<_, std::io::Error>(written)
+        }
+        .await;
+
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        }
+        Ok(written?)
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError> {
+        let data = tokio::fs::read(self.path_for(key)?).await?;
+        Ok(data.into())
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let file = tokio::fs::File::open(self.path_for(key)?).await?;
+        Ok(Box::pin(ReaderStream::new(file)))
+    }
+
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> Result<ByteStream, StorageError> {
+        let mut file = tokio::fs::File::open(self.path_for(key)?).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(Box::pin(ReaderStream::new(file.take(end - start + 1))))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match tokio::fs::remove_file(self.path_for(key)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn head(&self, key: &str) -> Result<ObjectMeta, StorageError> {
+        let metadata = tokio::fs::metadata(self.path_for(key)?).await?;
+        if !metadata.is_file() {
+            return Err(StorageError::NotFound);
+        }
+
+        Ok(ObjectMeta {
+            key: key.to_string(),
+            size: metadata.len(),
+            content_type: None,
+        })
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError> {
+        // Only walk the directory the prefix points into, not the whole root
+        let start = match prefix.rsplit_once('/') {
+            Some((dir, _)) if !dir.is_empty() => self.path_for(dir)?,
+            _ => self.root.clone(),
+        };
+
+        let mut objects = Vec::new();
+        let mut pending = vec![start];
+
+        while let Some(dir) = pending.pop() {
+            // A missing directory simply has nothing in it
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    pending.push(entry.path());
+                    continue;
+                }
+
+                let path = entry.path();
+                let Ok(relative) = path.strip_prefix(&self.root) else {
+                    continue;
+                };
+                let key = relative
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+
+                // Skip in-flight writes
+                if key.starts_with(prefix) && !key.ends_with(".tmp") {
+                    objects.push(ObjectMeta {
+                        key,
+                        size: entry.metadata().await?.len(),
+                        content_type: None,
+                    });
+                }
+            }
+        }
+
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let root = std::env::temp_dir().join(format!("flan-storage-{}", Uuid::new_v4()));
+        let storage = LocalStorage::new(root.clone()).await.unwrap();
+
+        crate::storage::assert_round_trip(&storage).await;
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keys_cannot_escape_the_root() {
+        let root = std::env::temp_dir().join(format!("flan-storage-{}", Uuid::new_v4()));
+        let storage = LocalStorage::new(root.clone()).await.unwrap();
+
+        for key in ["../outside", "/etc/passwd", ""] {
+            assert!(matches!(
+                storage.get(key).await,
+                Err(StorageError::InvalidKey(_))
+            ));
+        }
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+use super::{ByteStream, ObjectMeta, Storage, StorageError};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream;
+use std::{collections::BTreeMap, sync::RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Keeps images in process memory. Meant for tests and throwaway instances.
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: RwLock<BTreeMap<String, StoredObject>>,
+}
+
+struct StoredObject {
+    data: Bytes,
+    content_type: String,
+}
+
+impl StoredObject {
+    fn meta(&self, key: &str) -> ObjectMeta {
+        ObjectMeta {
+            key: key.to_string(),
+            size: self.data.len() as u64,
+            content_type: Some(self.content_type.clone()),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), StorageError> {
+        self.objects.write().unwrap().insert(
+            key.to_string(),
+            StoredObject {
+                data: Bytes::copy_from_slice(data),
+                content_type: content_type.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        content_type: &str,
+    ) -> Result<u64, StorageError> {
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .await
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+
+        let size = data.len() as u64;
+        self.objects.write().unwrap().insert(
+            key.to_string(),
+            StoredObject {
+                data: data.into(),
+                content_type: content_type.to_string(),
+            },
+        );
+        Ok(size)
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError> {
+        self.objects
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|object| object.data.clone())
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let data = self.get(key).await?;
+        Ok(Box::pin(stream::iter([Ok(data)])))
+    }
+
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> Result<ByteStream, StorageError> {
+        let data = self.get(key).await?;
+        let end = (end as usize).saturating_add(1).min(data.len());
+        let data = data.slice((start as usize).min(end)..end);
+        Ok(Box::pin(stream::iter([Ok(data)])))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.objects.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<ObjectMeta, StorageError> {
+        self.objects
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|object| object.meta(key))
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError> {
+        Ok(self
+            .objects
+            .read()
+            .unwrap()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, object)| object.meta(key))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        crate::storage::assert_round_trip(&MemoryStorage::default()).await;
+    }
+}
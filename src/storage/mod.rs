@@ -0,0 +1,162 @@
+//! Object storage backends for uploaded images.
+//!
+//! Handlers only talk to the [`Storage`] trait, the concrete backend is picked
+//! from the `[storage]` section of the config at startup.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+use common::config::{StorageBackend, StorageConfig};
+use futures_util::Stream;
+use std::{pin::Pin, sync::Arc};
+use tokio::io::AsyncRead;
+use tracing::info;
+
+pub mod local;
+pub mod memory;
+pub mod s3;
+
+pub use self::s3::S3Storage;
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound,
+    InvalidKey(String),
+    Backend(String),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound => write!(f, "Object not found"),
+            StorageError::InvalidKey(key) => write!(f, "Invalid object key: {}", key),
+            StorageError::Backend(err) => write!(f, "Storage backend error: {}", err),
+        }
+    }
+}
+
+/// The contents of an object, read a chunk at a time.
+pub type ByteStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// Metadata about a stored object.
+#[derive(Debug, Clone)]
+pub struct ObjectMeta {
+    pub key: String,
+    #[allow(dead_code)] // Only read by the backend tests so far
+    pub size: u64,
+    #[allow(dead_code)]
+    pub content_type: Option<String>,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores `data` under `key`, replacing any existing object.
+    async fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), StorageError>;
+
+    /// Streams `reader` into the object stored under `key` and returns the number of bytes
+    /// written. Backends keep memory usage bounded regardless of the object size.
+    async fn put_stream(
+        &self,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        content_type: &str,
+    ) -> Result<u64, StorageError>;
+
+    /// Fetches the whole object stored under `key`.
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError>;
+
+    /// Streams the object stored under `key`, without holding all of it in memory. A missing
+    /// object is reported before any of the stream is read.
+    async fn get_stream(&self, key: &str) -> Result<ByteStream, StorageError>;
+
+    /// Streams bytes `start` to `end`, inclusive, of the object stored under `key`. The range
+    /// is cut short at the end of the object.
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> Result<ByteStream, StorageError>;
+
+    /// Removes the object stored under `key`. Deleting a missing object is not an error.
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Fetches the metadata of the object stored under `key` without its contents.
+    #[allow(dead_code)] // Only used by the backend tests so far
+    async fn head(&self, key: &str) -> Result<ObjectMeta, StorageError>;
+
+    /// Lists all objects whose key starts with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError>;
+}
+
+pub type DynStorage = Arc<dyn Storage>;
+
+/// Builds the storage backend selected in the config.
+pub async fn from_config(config: StorageConfig) -> Result<DynStorage> {
+    let storage: DynStorage = match config.backend {
+        StorageBackend::S3 => {
+            let storage = S3Storage::connect(&config.s3).await?;
+            info!("Connected to S3 bucket {}", config.s3.bucket_name);
+            Arc::new(storage)
+        }
+        StorageBackend::Local => {
+            let storage = LocalStorage::new(config.local.path.clone()).await?;
+            info!("Using local storage at {}", config.local.path.display());
+            Arc::new(storage)
+        }
+        StorageBackend::Memory => {
+            info!("Using in-memory storage, images will not survive a restart");
+            Arc::new(MemoryStorage::default())
+        }
+    };
+
+    Ok(storage)
+}
+
+/// Checks the basic operations every backend has to support.
+#[cfg(test)]
+pub(crate) async fn assert_round_trip(storage: &dyn Storage) {
+    storage
+        .put("images/a.png", b"first", "image/png")
+        .await
+        .unwrap();
+    storage
+        .put("images/b.png", b"second", "image/png")
+        .await
+        .unwrap();
+    storage
+        .put("cache/a/1", b"variant", "application/octet-stream")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        storage.get("images/a.png").await.unwrap(),
+        Bytes::from_static(b"first")
+    );
+    assert_eq!(storage.head("images/b.png").await.unwrap().size, 6);
+
+    let keys = |objects: Vec<ObjectMeta>| objects.into_iter().map(|o| o.key).collect::<Vec<_>>();
+    assert_eq!(
+        keys(storage.list("images/").await.unwrap()),
+        ["images/a.png", "images/b.png"]
+    );
+    assert_eq!(
+        keys(storage.list("images/b").await.unwrap()),
+        ["images/b.png"]
+    );
+    assert_eq!(keys(storage.list("cache/").await.unwrap()), ["cache/a/1"]);
+    assert!(storage.list("missing/").await.unwrap().is_empty());
+
+    storage.delete("images/a.png").await.unwrap();
+    assert!(matches!(
+        storage.get("images/a.png").await,
+        Err(StorageError::NotFound)
+    ));
+    assert!(matches!(
+        storage.head("images/a.png").await,
+        Err(StorageError::NotFound)
+    ));
+    // Deleting twice is fine
+    storage.delete("images/a.png").await.unwrap();
+    assert_eq!(
+        keys(storage.list("images/").await.unwrap()),
+        ["images/b.png"]
+    );
+}
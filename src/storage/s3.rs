@@ -0,0 +1,204 @@
+use super::{ByteStream, ObjectMeta, Storage, StorageError};
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::{Result, WrapErr};
+use common::config::S3Config;
+use futures_util::TryStreamExt;
+use s3::{
+    command::Command,
+    creds::Credentials,
+    error::S3Error,
+    region::Region,
+    request::{HyperRequest, Request, ResponseDataStream},
+    Bucket, BucketConfiguration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::error;
+
+/// Stores images in an S3-compatible bucket.
+pub struct S3Storage {
+    bucket: Bucket,
+}
+
+impl S3Storage {
+    /// Connects to the configured bucket, creating it if it doesn't exist yet.
+    pub async fn connect(config: &S3Config) -> Result<Self> {
+        let region = Region::Custom {
+            region: config.region.clone(),
+            endpoint: config.endpoint.clone(),
+        };
+        let credentials = Credentials::new(
+            Some(&config.access_key),
+            Some(&config.secret_key),
+            None,
+            None,
+            None,
+        )?;
+
+        let mut bucket = Bucket::new(&config.bucket_name, region.clone(), credentials.clone())?
+            .with_path_style();
+
+        let bkt_exists = bucket.exists().await?;
+
+        // Create bucket if it doesn't exist
+        if !bkt_exists {
+            bucket = Bucket::create_with_path_style(
+                &config.bucket_name,
+                region,
+                credentials,
+                BucketConfiguration::default(),
+            )
+            .await
+            .with_context(|| format!("Failed to create bucket {}", config.bucket_name))?
+            .bucket;
+        }
+
+        Ok(Self { bucket })
+    }
+}
+
+/// Size of each part of a multipart upload. S3 requires at least 5 MiB.
+const MULTIPART_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Reads up to [`MULTIPART_CHUNK_SIZE`] bytes, less only at the end of the stream.
+async fn read_chunk(reader: &mut (dyn AsyncRead + Send + Unpin)) -> Result<Vec<u8>, StorageError> {
+    let mut chunk = Vec::with_capacity(MULTIPART_CHUNK_SIZE);
+    reader
+        .take(MULTIPART_CHUNK_SIZE as u64)
+        .read_to_end(&mut chunk)
+        .await
+        .map_err(|e| StorageError::Backend(e.to_string()))?;
+    Ok(chunk)
+}
+
+/// The contents of a streamed response.
+fn data_stream(object: ResponseDataStream) -> ByteStream {
+    // The body also carries trailers, only its data frames are part of the object
+    let stream = object
+        .body_stream
+        .try_filter_map(|frame| async move { Ok(frame.into_data().ok()) })
+        .map_err(std::io::Error::other);
+    Box::pin(stream)
+}
+
+impl From<S3Error> for StorageError {
+    fn from(error: S3Error) -> StorageError {
+        match error {
+            S3Error::HttpFailWithBody(404, _) => StorageError::NotFound,
+            other => StorageError::Backend(other.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), StorageError> {
+        self.bucket
+            .put_object_with_content_type(key, data, content_type)
+            .await?;
+        Ok(())
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        content_type: &str,
+    ) -> Result<u64, StorageError> {
+        // Small objects fit in a single request
+        let first_chunk = read_chunk(reader).await?;
+        if first_chunk.len() < MULTIPART_CHUNK_SIZE {
+            self.put(key, &first_chunk, content_type).await?;
+            return Ok(first_chunk.len() as u64);
+        }
+
+        // Larger ones are sent as a multipart upload, one chunk in memory at a time
+        let upload = self
+            .bucket
+            .initiate_multipart_upload(key, content_type)
+            .await?;
+
+        let result = async {
+            let mut parts = Vec::new();
+            let mut chunk = first_chunk;
+            let mut total = 0;
+            while !chunk.is_empty() {
+                total += chunk.len() as u64;
+                let part_number = parts.len() as u32 + 1;
+                let part = self
+                    .bucket
+                    .put_multipart_chunk(chunk, key, part_number, &upload.upload_id, content_type)
+                    .await?;
+                parts.push(part);
+                chunk = read_chunk(reader).await?;
+            }
+
+            self.bucket
+                .complete_multipart_upload(key, &upload.upload_id, parts)
+                .await?;
+            Ok::<_, StorageError>(total)
+        }
+        .await;
+
+        if result.is_err() {
+            if let Err(e) = self.bucket.abort_upload(key, &upload.upload_id).await {
+                error!("Failed to abort multipart upload of {}: {}", key, e);
+            }
+        }
+        result
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError> {
+        let object = self.bucket.get_object(key).await?;
+        Ok(object.bytes().clone())
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let object = self.bucket.get_object_stream(key).await?;
+        Ok(data_stream(object))
+    }
+
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> Result<ByteStream, StorageError> {
+        // The bucket only streams whole objects, so the ranged request is made directly
+        let command = Command::GetObjectRange {
+            start,
+            end: Some(end),
+        };
+        let object = HyperRequest::new(&self.bucket, key, command)?
+            .response_data_to_stream()
+            .await?;
+        Ok(data_stream(object))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.bucket.delete_object(key).await?;
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<ObjectMeta, StorageError> {
+        let (head, status) = self.bucket.head_object(key).await?;
+        if status == 404 {
+            return Err(StorageError::NotFound);
+        }
+
+        Ok(ObjectMeta {
+            key: key.to_string(),
+            size: head.content_length.unwrap_or_default().max(0) as u64,
+            content_type: head.content_type,
+        })
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError> {
+        let pages = self.bucket.list(prefix.to_string(), None).await?;
+
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| ObjectMeta {
+                key: object.key,
+                size: object.size,
+                content_type: None,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,865 @@
+//! The transform pipeline behind image requests: decode, apply the requested operations in a
+//! fixed order, encode.
+//!
+//! Operations always run in this order, whatever the order of the query parameters:
+//! EXIF orientation, crop, rotate, flip, resize, blur, sharpen, grayscale, brightness,
+//! contrast. Crop rectangles are in pixels of the upright original, and width and height are
+//! those of the final image.
+
+mod pool;
+mod resize;
+
+pub use pool::{PoolError, TransformPool};
+
+use crate::validation::decode_limits;
+use common::config::LimitsConfig;
+use common::transform::{Fit, Flip, Gravity, TransformParams};
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder},
+    metadata::Orientation,
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader, Rgba,
+};
+use std::io::Cursor;
+
+/// Fills the space around padded images unless the request picks a color.
+const DEFAULT_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+const DEFAULT_QUALITY: u8 = 80;
+
+/// AVIF encoder speed, from 1 (smallest files) to 10 (fastest). Images are encoded on request,
+/// so this leans towards speed.
+const AVIF_SPEED: u8 = 8;
+
+/// Largest blur and sharpen sigma, their cost grows with it.
+const MAX_SIGMA: f32 = 50.0;
+
+/// Differences smaller than this are left alone when sharpening, so noise isn't amplified.
+const SHARPEN_THRESHOLD: i32 = 1;
+
+#[derive(Debug)]
+pub enum TransformError {
+    InvalidParams(String),
+    Decode(String),
+    Encode(String),
+}
+
+impl std::fmt::Display for TransformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransformError::InvalidParams(err) => write!(f, "Invalid parameters: {}", err),
+            TransformError::Decode(err) => write!(f, "Failed to decode image: {}", err),
+            TransformError::Encode(err) => write!(f, "Failed to encode image: {}", err),
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> TransformError {
+    TransformError::InvalidParams(message.into())
+}
+
+/// A single step of the pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    Orient(Orientation),
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Clockwise, in degrees: 90, 180 or 270.
+    Rotate(u16),
+    Flip(Flip),
+    Resize {
+        width: Option<u32>,
+        height: Option<u32>,
+        fit: Fit,
+        gravity: Gravity,
+        background: Rgba<u8>,
+    },
+    Blur(f32),
+    Sharpen(f32),
+    Grayscale,
+    Brightness(i32),
+    Contrast(f32),
+}
+
+impl Operation {
+    pub fn apply(&self, mut img: DynamicImage) -> Result<DynamicImage, TransformError> {
+        Ok(match *self {
+            Operation::Orient(orientation) => {
+                img.apply_orientation(orientation);
+                img
+            }
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                if u64::from(x) + u64::from(width) > u64::from(img.width())
+                    || u64::from(y) + u64::from(height) > u64::from(img.height())
+                {
+                    return Err(invalid(format!(
+                        "Crop rectangle is outside the {}x{} image",
+                        img.width(),
+                        img.height()
+                    )));
+                }
+                img.crop_imm(x, y, width, height)
+            }
+            Operation::Rotate(90) => img.rotate90(),
+            Operation::Rotate(180) => img.rotate180(),
+            Operation::Rotate(270) => img.rotate270(),
+            Operation::Rotate(_) => img,
+            Operation::Flip(Flip::Horizontal) => img.fliph(),
+            Operation::Flip(Flip::Vertical) => img.flipv(),
+            // Flipping both ways is a half turn
+            Operation::Flip(Flip::Both) => img.rotate180(),
+            Operation::Resize {
+                width,
+                height,
+                fit,
+                gravity,
+                background,
+            } => resize::resize(img, width, height, fit, gravity, background),
+            Operation::Blur(sigma) => img.blur(sigma),
+            Operation::Sharpen(sigma) => img.unsharpen(sigma, SHARPEN_THRESHOLD),
+            Operation::Grayscale => img.grayscale(),
+            Operation::Brightness(value) => img.brighten(value),
+            Operation::Contrast(percent) => img.adjust_contrast(percent),
+        })
+    }
+}
+
+/// Format an image is encoded to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    /// The original format, or PNG or JPEG for formats that are only decoded.
+    Original,
+    Format(ImageFormat),
+    /// `format=auto`, until it's negotiated.
+    Auto,
+    /// `format=auto` for clients that take neither AVIF nor WebP: JPEG, or PNG to keep
+    /// transparency.
+    Fallback,
+}
+
+/// The operations and output settings of a request, checked before anything is fetched.
+#[derive(Debug)]
+pub struct Pipeline {
+    auto_orient: bool,
+    operations: Vec<Operation>,
+    output: Output,
+    quality: u8,
+}
+
+impl Pipeline {
+    pub fn new(params: &TransformParams, limits: &LimitsConfig) -> Result<Self, TransformError> {
+        let mut operations = Vec::new();
+
+        if let Some(crop) = &params.crop {
+            operations.push(parse_crop(crop)?);
+        }
+
+        if let Some(degrees) = params.rotate {
+            match degrees.rem_euclid(360) {
+                0 => {}
+                degrees @ (90 | 180 | 270) => operations.push(Operation::Rotate(degrees as u16)),
+                _ => return Err(invalid("Rotation must be a multiple of 90 degrees")),
+            }
+        }
+
+        if let Some(flip) = params.flip {
+            operations.push(Operation::Flip(flip));
+        }
+
+        if params
+            .width
+            .is_some_and(|width| width == 0 || width > limits.max_width)
+            || params
+                .height
+                .is_some_and(|height| height == 0 || height > limits.max_height)
+        {
+            return Err(invalid(format!(
+                "Width and height must be between 1 and {}x{}",
+                limits.max_width, limits.max_height
+            )));
+        }
+        if let (Some(width), Some(height)) = (params.width, params.height) {
+            if u64::from(width) * u64::from(height) > limits.max_pixels {
+                return Err(invalid(format!(
+                    "Width and height must not exceed {} pixels",
+                    limits.max_pixels
+                )));
+            }
+        }
+        let background = match &params.background {
+            Some(color) => resize::parse_color(color)
+                .ok_or_else(|| invalid(format!("Invalid background color: {}", color)))?,
+            None => DEFAULT_BACKGROUND,
+        };
+        if params.width.is_some() || params.height.is_some() {
+            operations.push(Operation::Resize {
+                width: params.width,
+                height: params.height,
+                fit: params.fit.unwrap_or_default(),
+                gravity: params.gravity.unwrap_or_default(),
+                background,
+            });
+        }
+
+        if let Some(sigma) = params.blur {
+            operations.push(Operation::Blur(parse_sigma("Blur", sigma)?));
+        }
+
+        if let Some(sigma) = params.sharpen {
+            operations.push(Operation::Sharpen(parse_sigma("Sharpen", sigma)?));
+        }
+
+        if params.grayscale == Some(true) {
+            operations.push(Operation::Grayscale);
+        }
+
+        if let Some(value) = params.brightness {
+            if !(-255..=255).contains(&value) {
+                return Err(invalid("Brightness must be between -255 and 255"));
+            }
+            operations.push(Operation::Brightness(value));
+        }
+
+        if let Some(percent) = params.contrast {
+            if !(-100.0..=100.0).contains(&percent) {
+                return Err(invalid("Contrast must be between -100 and 100"));
+            }
+            operations.push(Operation::Contrast(percent));
+        }
+
+        let output = match params.format.as_deref() {
+            None => Output::Original,
+            Some("jpeg") | Some("jpg") => Output::Format(ImageFormat::Jpeg),
+            Some("png") => Output::Format(ImageFormat::Png),
+            Some("webp") => Output::Format(ImageFormat::WebP),
+            Some("avif") => Output::Format(ImageFormat::Avif),
+            Some("auto") => Output::Auto,
+            Some(format) => return Err(invalid(format!("Unsupported format: {}", format))),
+        };
+
+        let quality = params.quality.unwrap_or(DEFAULT_QUALITY);
+        if !(1..=100).contains(&quality) {
+            return Err(invalid("Quality must be between 1 and 100"));
+        }
+
+        Ok(Pipeline {
+            auto_orient: params.auto_orient.unwrap_or(true),
+            operations,
+            output,
+            quality,
+        })
+    }
+
+    /// Picks the output format of `format=auto` from the request's `Accept` header. Returns
+    /// what was picked, as responses then depend on the header, or `None` without `auto`.
+    pub fn negotiate(&mut self, accept: Option<&str>) -> Option<&'static str> {
+        if self.output != Output::Auto {
+            return None;
+        }
+
+        let (output, name) = match preferred_format(accept.unwrap_or_default()) {
+            Some(ImageFormat::Avif) => (Output::Format(ImageFormat::Avif), "avif"),
+            Some(ImageFormat::WebP) => (Output::Format(ImageFormat::WebP), "webp"),
+            _ => (Output::Fallback, "fallback"),
+        };
+        self.output = output;
+        Some(name)
+    }
+
+    /// Decodes `data`, runs the operations and encodes the result, returning it with its
+    /// content type.
+    pub fn run(
+        &self,
+        data: &[u8],
+        limits: &LimitsConfig,
+    ) -> Result<(Vec<u8>, &'static str), TransformError> {
+        let (mut img, orientation) = decode(data, limits)?;
+
+        if self.auto_orient {
+            img = Operation::Orient(orientation).apply(img)?;
+        }
+        for operation in &self.operations {
+            if let Operation::Resize {
+                width, height, fit, ..
+            } = *operation
+            {
+                let (width, height) =
+                    resize::planned_size((img.width(), img.height()), width, height, fit);
+                if width > u32::MAX.into()
+                    || height > u32::MAX.into()
+                    || width * height > limits.max_pixels
+                {
+                    return Err(invalid(format!(
+                        "Resized image would be {}x{}, more than {} pixels",
+                        width, height, limits.max_pixels
+                    )));
+                }
+            }
+            img = operation.apply(img)?;
+        }
+
+        let format = match self.output {
+            Output::Format(format) => format,
+            Output::Original => match image::guess_format(data).unwrap_or(ImageFormat::Jpeg) {
+                format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP) => format,
+                _ if img.color().has_alpha() => ImageFormat::Png,
+                _ => ImageFormat::Jpeg,
+            },
+            // Without negotiation, stick to what every client can show
+            Output::Auto | Output::Fallback if img.color().has_alpha() => ImageFormat::Png,
+            Output::Auto | Output::Fallback => ImageFormat::Jpeg,
+        };
+        encode(&img, format, self.quality)
+    }
+}
+
+/// The most preferred of AVIF and WebP in an `Accept` header, if the client takes either.
+/// Wildcards don't count, as browsers send them whatever they support. AVIF wins ties, its
+/// files are smaller.
+fn preferred_format(accept: &str) -> Option<ImageFormat> {
+    let mut best: Option<(ImageFormat, f32)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let format = match parts.next().unwrap_or_default().trim() {
+            media if media.eq_ignore_ascii_case("image/avif") => ImageFormat::Avif,
+            media if media.eq_ignore_ascii_case("image/webp") => ImageFormat::WebP,
+            _ => continue,
+        };
+        let quality = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+
+        if quality > 0.0
+            && best.is_none_or(|(_, best_quality)| {
+                quality > best_quality || (quality == best_quality && format == ImageFormat::Avif)
+            })
+        {
+            best = Some((format, quality));
+        }
+    }
+    best.map(|(format, _)| format)
+}
+
+fn parse_crop(crop: &str) -> Result<Operation, TransformError> {
+    let parts = crop
+        .split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>();
+    match parts.as_deref() {
+        Ok(&[x, y, width, height]) if width > 0 && height > 0 => Ok(Operation::Crop {
+            x,
+            y,
+            width,
+            height,
+        }),
+        _ => Err(invalid(
+            "Crop must be x,y,width,height with a non-empty area",
+        )),
+    }
+}
+
+fn parse_sigma(name: &str, sigma: f32) -> Result<f32, TransformError> {
+    if sigma > 0.0 && sigma <= MAX_SIGMA {
+        Ok(sigma)
+    } else {
+        Err(invalid(format!(
+            "{} must be greater than 0 and at most {}",
+            name, MAX_SIGMA
+        )))
+    }
+}
+
+fn decode(
+    data: &[u8],
+    limits: &LimitsConfig,
+) -> Result<(DynamicImage, Orientation), TransformError> {
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| TransformError::Decode(e.to_string()))?;
+    let mut limits = decode_limits(limits);
+    reader.limits(limits.clone());
+
+    let mut decoder = reader
+        .into_decoder()
+        .map_err(|e| TransformError::Decode(e.to_string()))?;
+    let orientation = decoder
+        .orientation()
+        .map_err(|e| TransformError::Decode(e.to_string()))?;
+    limits
+        .reserve(decoder.total_bytes())
+        .map_err(|e| TransformError::Decode(e.to_string()))?;
+    let img =
+        DynamicImage::from_decoder(decoder).map_err(|e| TransformError::Decode(e.to_string()))?;
+
+    Ok((img, orientation))
+}
+
+fn encode(
+    img: &DynamicImage,
+    format: ImageFormat,
+    quality: u8,
+) -> Result<(Vec<u8>, &'static str), TransformError> {
+    let mut buffer = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let encoder = JpegEncoder::new_with_quality(&mut buffer, quality);
+            DynamicImage::ImageRgb8(img.to_rgb8()).write_with_encoder(encoder)
+        }
+        ImageFormat::WebP => {
+            // The image crate only writes lossless WebP, libwebp honors the quality
+            let rgba = img.to_rgba8();
+            let webp = webp::Encoder::from_rgba(&rgba, img.width(), img.height())
+                .encode_simple(false, f32::from(quality))
+                .map_err(|e| TransformError::Encode(format!("{:?}", e)))?;
+            buffer.extend_from_slice(&webp);
+            Ok(())
+        }
+        ImageFormat::Avif => {
+            let encoder = AvifEncoder::new_with_speed_quality(&mut buffer, AVIF_SPEED, quality);
+            let img = match img.color().has_alpha() {
+                true => DynamicImage::ImageRgba8(img.to_rgba8()),
+                false => DynamicImage::ImageRgb8(img.to_rgb8()),
+            };
+            img.write_with_encoder(encoder)
+        }
+        format => img.write_to(&mut Cursor::new(&mut buffer), format),
+    }
+    .map_err(|e| TransformError::Encode(e.to_string()))?;
+
+    Ok((buffer, format.to_mime_type()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+    const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+
+    fn limits() -> LimitsConfig {
+        LimitsConfig {
+            max_file_size: 1 << 20,
+            max_files_per_request: 1,
+            upload_timeout: 600,
+            max_width: 1000,
+            max_height: 1000,
+            max_pixels: 1_000_000,
+            quota_bytes: None,
+            quota_images: None,
+        }
+    }
+
+    /// A 4x2 image, red on the left half and blue on the right.
+    fn halves() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(
+            4,
+            2,
+            |x, _| if x < 2 { RED } else { BLUE },
+        ))
+    }
+
+    /// A gradient, so blur and sharpen have edges to work on.
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| {
+            Rgb([
+                (x * 16) as u8,
+                (y * 16) as u8,
+                if (x + y) % 2 == 0 { 0 } else { 255 },
+            ])
+        }))
+    }
+
+    fn pixel(img: &DynamicImage, x: u32, y: u32) -> [u8; 3] {
+        let [r, g, b, _] = img.get_pixel(x, y).0;
+        [r, g, b]
+    }
+
+    fn params() -> TransformParams {
+        TransformParams::default()
+    }
+
+    #[test]
+    fn orient_applies_exif_rotation() {
+        let img = Operation::Orient(Orientation::Rotate90)
+            .apply(halves())
+            .unwrap();
+        assert_eq!(img.dimensions(), (2, 4));
+        assert_eq!(pixel(&img, 0, 0), RED.0);
+        assert_eq!(pixel(&img, 0, 3), BLUE.0);
+    }
+
+    #[test]
+    fn crop_cuts_out_rectangle() {
+        let crop = parse_crop("1,0,2,2").unwrap();
+        let img = crop.apply(halves()).unwrap();
+        assert_eq!(img.dimensions(), (2, 2));
+        assert_eq!(pixel(&img, 0, 0), RED.0);
+        assert_eq!(pixel(&img, 1, 0), BLUE.0);
+    }
+
+    #[test]
+    fn crop_rejects_rectangle_outside_image() {
+        let crop = parse_crop("3,0,2,2").unwrap();
+        assert!(matches!(
+            crop.apply(halves()),
+            Err(TransformError::InvalidParams(_))
+        ));
+        assert!(parse_crop("0,0,0,2").is_err());
+        assert!(parse_crop("0,0,2").is_err());
+        assert!(parse_crop("a,b,c,d").is_err());
+    }
+
+    #[test]
+    fn rotate_turns_clockwise() {
+        let img = Operation::Rotate(90).apply(halves()).unwrap();
+        assert_eq!(img.dimensions(), (2, 4));
+        assert_eq!(pixel(&img, 0, 0), RED.0);
+        assert_eq!(pixel(&img, 0, 3), BLUE.0);
+
+        let img = Operation::Rotate(180).apply(halves()).unwrap();
+        assert_eq!(pixel(&img, 0, 0), BLUE.0);
+
+        let img = Operation::Rotate(270).apply(halves()).unwrap();
+        assert_eq!(img.dimensions(), (2, 4));
+        assert_eq!(pixel(&img, 0, 0), BLUE.0);
+    }
+
+    #[test]
+    fn rotate_accepts_only_quarter_turns() {
+        let pipeline = |rotate| {
+            Pipeline::new(
+                &TransformParams {
+                    rotate: Some(rotate),
+                    ..params()
+                },
+                &limits(),
+            )
+        };
+        assert_eq!(pipeline(-90).unwrap().operations, [Operation::Rotate(270)]);
+        assert_eq!(pipeline(360).unwrap().operations, []);
+        assert!(pipeline(45).is_err());
+    }
+
+    #[test]
+    fn flip_mirrors_image() {
+        let img = Operation::Flip(Flip::Horizontal).apply(halves()).unwrap();
+        assert_eq!(pixel(&img, 0, 0), BLUE.0);
+
+        let img = Operation::Flip(Flip::Vertical).apply(halves()).unwrap();
+        assert_eq!(pixel(&img, 0, 0), RED.0);
+
+        let img = Operation::Flip(Flip::Both).apply(halves()).unwrap();
+        assert_eq!(pixel(&img, 0, 0), BLUE.0);
+    }
+
+    #[test]
+    fn resize_follows_fit_mode() {
+        let resize = |fit| Operation::Resize {
+            width: Some(2),
+            height: Some(2),
+            fit,
+            gravity: Gravity::Center,
+            background: Rgba([0, 255, 0, 255]),
+        };
+        let contain = resize(Fit::Contain).apply(halves()).unwrap();
+        assert_eq!(contain.dimensions(), (2, 1));
+
+        let cover = resize(Fit::Cover).apply(halves()).unwrap();
+        assert_eq!(cover.dimensions(), (2, 2));
+
+        let fill = resize(Fit::Fill).apply(halves()).unwrap();
+        assert_eq!(fill.dimensions(), (2, 2));
+
+        let pad = resize(Fit::Pad).apply(halves()).unwrap();
+        assert_eq!(pad.dimensions(), (2, 2));
+        assert_eq!(pixel(&pad, 0, 1), [0, 255, 0]);
+    }
+
+    #[test]
+    fn resize_keeps_aspect_ratio_with_one_dimension() {
+        let img = Operation::Resize {
+            width: Some(2),
+            height: None,
+            fit: Fit::Fill,
+            gravity: Gravity::Center,
+            background: DEFAULT_BACKGROUND,
+        }
+        .apply(halves())
+        .unwrap();
+        assert_eq!(img.dimensions(), (2, 1));
+    }
+
+    #[test]
+    fn resize_output_is_limited_by_max_pixels() {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(1, 1000))
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        let run = |params: TransformParams| {
+            Pipeline::new(&params, &limits())
+                .and_then(|pipeline| pipeline.run(&data, &limits()))
+                .map(|_| ())
+        };
+
+        // A single side follows the extreme aspect ratio: 1000x1000000
+        let err = run(TransformParams {
+            width: Some(1000),
+            ..params()
+        });
+        assert!(matches!(err, Err(TransformError::InvalidParams(_))));
+
+        // Cover scales to 500x500000 before cropping
+        let err = run(TransformParams {
+            width: Some(500),
+            height: Some(500),
+            fit: Some(Fit::Cover),
+            ..params()
+        });
+        assert!(matches!(err, Err(TransformError::InvalidParams(_))));
+
+        assert!(run(TransformParams {
+            height: Some(1000),
+            ..params()
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn requested_size_is_limited_by_max_pixels() {
+        let limits = LimitsConfig {
+            max_pixels: 10_000,
+            ..limits()
+        };
+        let pipeline = |fit| {
+            Pipeline::new(
+                &TransformParams {
+                    width: Some(200),
+                    height: Some(100),
+                    fit: Some(fit),
+                    ..params()
+                },
+                &limits,
+            )
+        };
+        assert!(matches!(
+            pipeline(Fit::Fill),
+            Err(TransformError::InvalidParams(_))
+        ));
+        assert!(matches!(
+            pipeline(Fit::Pad),
+            Err(TransformError::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn blur_smooths_edges() {
+        let img = Operation::Blur(2.0).apply(gradient()).unwrap();
+        assert_eq!(img.dimensions(), (16, 16));
+        let [_, _, b] = pixel(&img, 8, 8);
+        assert!(b > 0 && b < 255);
+    }
+
+    #[test]
+    fn sharpen_keeps_dimensions_and_changes_pixels() {
+        let original = gradient();
+        let img = Operation::Sharpen(1.0).apply(original.clone()).unwrap();
+        assert_eq!(img.dimensions(), original.dimensions());
+        assert_ne!(img.to_rgb8(), original.to_rgb8());
+    }
+
+    #[test]
+    fn sigma_must_be_in_range() {
+        assert!(parse_sigma("Blur", 0.0).is_err());
+        assert!(parse_sigma("Blur", MAX_SIGMA + 1.0).is_err());
+        assert_eq!(parse_sigma("Blur", 1.5).unwrap(), 1.5);
+    }
+
+    #[test]
+    fn grayscale_drops_color() {
+        let img = Operation::Grayscale.apply(halves()).unwrap();
+        let [r, g, b] = pixel(&img, 0, 0);
+        assert!(r == g && g == b);
+    }
+
+    #[test]
+    fn brightness_shifts_channels() {
+        let img = Operation::Brightness(-55).apply(halves()).unwrap();
+        assert_eq!(pixel(&img, 0, 0), [200, 0, 0]);
+
+        assert!(Pipeline::new(
+            &TransformParams {
+                brightness: Some(300),
+                ..params()
+            },
+            &limits()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn contrast_spreads_channels() {
+        let gray = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb([160, 160, 160])));
+        let more = Operation::Contrast(50.0).apply(gray.clone()).unwrap();
+        let less = Operation::Contrast(-50.0).apply(gray).unwrap();
+        assert!(pixel(&more, 0, 0)[0] > 160);
+        assert!(pixel(&less, 0, 0)[0] < 160);
+    }
+
+    #[test]
+    fn pipeline_orders_operations() {
+        let pipeline = Pipeline::new(
+            &TransformParams {
+                contrast: Some(10.0),
+                grayscale: Some(true),
+                width: Some(10),
+                flip: Some(Flip::Vertical),
+                crop: Some("0,0,4,4".to_string()),
+                ..params()
+            },
+            &limits(),
+        )
+        .unwrap();
+
+        let names = pipeline
+            .operations
+            .iter()
+            .map(|operation| match operation {
+                Operation::Crop { .. } => "crop",
+                Operation::Flip(_) => "flip",
+                Operation::Resize { .. } => "resize",
+                Operation::Grayscale => "grayscale",
+                Operation::Contrast(_) => "contrast",
+                _ => "other",
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["crop", "flip", "resize", "grayscale", "contrast"]);
+    }
+
+    fn encode_with(format: &str, quality: u8) -> (Vec<u8>, &'static str) {
+        let mut data = Vec::new();
+        gradient()
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+
+        let pipeline = Pipeline::new(
+            &TransformParams {
+                format: Some(format.to_string()),
+                quality: Some(quality),
+                ..params()
+            },
+            &limits(),
+        )
+        .unwrap();
+        pipeline.run(&data, &limits()).unwrap()
+    }
+
+    #[test]
+    fn webp_is_lossy_and_honors_quality() {
+        let (low, content_type) = encode_with("webp", 10);
+        let (high, _) = encode_with("webp", 100);
+        assert_eq!(content_type, "image/webp");
+        assert!(low.len() < high.len());
+        assert_eq!(image::guess_format(&low).unwrap(), ImageFormat::WebP);
+    }
+
+    #[test]
+    fn avif_is_encoded() {
+        let (data, content_type) = encode_with("avif", 50);
+        assert_eq!(content_type, "image/avif");
+        assert_eq!(&data[4..12], b"ftypavif");
+    }
+
+    #[test]
+    fn decoded_only_formats_fall_back_to_jpeg() {
+        let mut data = Vec::new();
+        halves()
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Bmp)
+            .unwrap();
+
+        let pipeline = Pipeline::new(
+            &TransformParams {
+                width: Some(2),
+                ..params()
+            },
+            &limits(),
+        )
+        .unwrap();
+        let (_, content_type) = pipeline.run(&data, &limits()).unwrap();
+        assert_eq!(content_type, "image/jpeg");
+    }
+
+    #[test]
+    fn accept_header_picks_smallest_supported_format() {
+        assert_eq!(
+            preferred_format("image/avif,image/webp,image/apng,image/*,*/*;q=0.8"),
+            Some(ImageFormat::Avif)
+        );
+        assert_eq!(preferred_format("image/webp,*/*"), Some(ImageFormat::WebP));
+        assert_eq!(
+            preferred_format("image/avif;q=0.5, image/webp"),
+            Some(ImageFormat::WebP)
+        );
+        assert_eq!(preferred_format("image/avif;q=0, image/png"), None);
+        assert_eq!(preferred_format("image/*,*/*"), None);
+    }
+
+    #[test]
+    fn auto_format_is_negotiated() {
+        let auto = || {
+            Pipeline::new(
+                &TransformParams {
+                    format: Some("auto".to_string()),
+                    ..params()
+                },
+                &limits(),
+            )
+            .unwrap()
+        };
+
+        let mut pipeline = auto();
+        assert_eq!(pipeline.negotiate(Some("image/webp")), Some("webp"));
+        assert_eq!(pipeline.output, Output::Format(ImageFormat::WebP));
+
+        let mut pipeline = auto();
+        assert_eq!(pipeline.negotiate(None), Some("fallback"));
+        assert_eq!(pipeline.output, Output::Fallback);
+
+        let mut pipeline = Pipeline::new(&params(), &limits()).unwrap();
+        assert_eq!(pipeline.negotiate(Some("image/avif")), None);
+        assert_eq!(pipeline.output, Output::Original);
+    }
+
+    #[test]
+    fn pipeline_runs_end_to_end() {
+        let mut data = Vec::new();
+        halves()
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+
+        let pipeline = Pipeline::new(
+            &TransformParams {
+                rotate: Some(90),
+                width: Some(1),
+                ..params()
+            },
+            &limits(),
+        )
+        .unwrap();
+        let (output, content_type) = pipeline.run(&data, &limits()).unwrap();
+        assert_eq!(content_type, "image/png");
+
+        let img = image::load_from_memory(&output).unwrap();
+        assert_eq!(img.dimensions(), (1, 2));
+    }
+}
@@ -0,0 +1,227 @@
+//! Runs transforms on Tokio's blocking threads, so decoding and encoding never stall the async
+//! workers. Only a bounded number run at once, and only a bounded number may wait for a slot:
+//! past that, requests are turned away rather than piling up until they time out.
+
+use common::admin::{TimingStats, TransformStats};
+use common::config::TransformConfig;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+#[derive(Debug)]
+pub enum PoolError {
+    /// Too many transforms are waiting already.
+    Busy,
+    /// The transform panicked.
+    Panicked,
+}
+
+impl std::fmt::Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PoolError::Busy => write!(f, "Too many transforms queued"),
+            PoolError::Panicked => write!(f, "Transform panicked"),
+        }
+    }
+}
+
+/// Running count and duration totals, in microseconds.
+#[derive(Default)]
+struct Timer {
+    count: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Timer {
+    fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(micros, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> TimingStats {
+        let count = self.count.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed) as f64 / 1000.0;
+        TimingStats {
+            count,
+            mean_ms: if count == 0 {
+                0.0
+            } else {
+                total / count as f64
+            },
+            max_ms: self.max.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+/// Leaves the queue when dropped, also when the request is cancelled while waiting.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct TransformPool {
+    slots: Arc<Semaphore>,
+    eager: Semaphore,
+    max_concurrent: usize,
+    max_queued: usize,
+    pub retry_after: u64,
+    queued: AtomicUsize,
+    rejected: AtomicU64,
+    queue_wait: Timer,
+    transform_time: Timer,
+}
+
+impl TransformPool {
+    pub fn new(config: &TransformConfig) -> Self {
+        let max_concurrent = config
+            .max_concurrent
+            .or_else(|| std::thread::available_parallelism().ok().map(Into::into))
+            .unwrap_or(1)
+            .max(1);
+
+        Self {
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            eager: Semaphore::new(config.max_eager.max(1)),
+            max_concurrent,
+            max_queued: config.max_queued,
+            retry_after: config.retry_after,
+            queued: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            queue_wait: Timer::default(),
+            transform_time: Timer::default(),
+        }
+    }
+
+    /// Runs `transform` once a slot is free, or fails right away if the queue is full.
+    pub async fn run<T, F>(&self, transform: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queued + self.available() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(PoolError::Busy);
+        }
+        let queued = Queued(&self.queued);
+
+        let waiting_since = Instant::now();
+        let slot = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        drop(queued);
+        self.queue_wait.record(waiting_since.elapsed());
+
+        // The slot goes with the transform, which keeps running if the request goes away
+        let (result, elapsed) = tokio::task::spawn_blocking(move || {
+            let _slot = slot;
+            let started = Instant::now();
+            let result = transform();
+            (result, started.elapsed())
+        })
+        .await
+        .map_err(|_| PoolError::Panicked)?;
+        self.transform_time.record(elapsed);
+
+        Ok(result)
+    }
+
+    /// Waits until another eager variant may be rendered. Eager renders hold on to this while
+    /// they run, so only a few of them take slots from requests at once.
+    pub async fn eager_slot(&self) -> SemaphorePermit<'_> {
+        self.eager
+            .acquire()
+            .await
+            .expect("the semaphore is never closed")
+    }
+
+    fn available(&self) -> usize {
+        self.slots.available_permits()
+    }
+
+    pub fn stats(&self) -> TransformStats {
+        TransformStats {
+            max_concurrent: self.max_concurrent,
+            running: self.max_concurrent - self.available(),
+            max_queued: self.max_queued,
+            queued: self.queued.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            queue_wait: self.queue_wait.stats(),
+            transform_time: self.transform_time.stats(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::mpsc};
+
+    fn pool(max_concurrent: usize, max_queued: usize) -> Arc<TransformPool> {
+        Arc::new(TransformPool::new(&TransformConfig {
+            require_signature: false,
+            max_concurrent: Some(max_concurrent),
+            max_queued,
+            retry_after: 1,
+            presets: HashMap::new(),
+            eager: Vec::new(),
+            max_eager: 1,
+        }))
+    }
+
+    #[tokio::test]
+    async fn runs_transforms() {
+        let pool = pool(2, 0);
+        assert_eq!(pool.run(|| 1 + 1).await.unwrap(), 2);
+
+        let stats = pool.stats();
+        assert_eq!(stats.running, 0);
+        assert_eq!(stats.queue_wait.count, 1);
+        assert_eq!(stats.transform_time.count, 1);
+    }
+
+    #[tokio::test]
+    async fn turns_transforms_away_when_the_queue_is_full() {
+        let pool = pool(1, 0);
+        let (release, blocked) = mpsc::channel::<()>();
+        let running = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(move || blocked.recv().unwrap()).await }
+        });
+        while pool.stats().running == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(pool.run(|| ()).await, Err(PoolError::Busy)));
+        release.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        pool.run(|| ()).await.unwrap();
+
+        let stats = pool.stats();
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.transform_time.count, 2);
+    }
+
+    #[tokio::test]
+    async fn reports_panics() {
+        let pool = pool(1, 0);
+        let result = pool.run(|| panic!("transform failed")).await;
+        assert!(matches!(result, Err(PoolError::Panicked)));
+        assert_eq!(pool.stats().running, 0);
+    }
+}
@@ -0,0 +1,186 @@
+//! Resizing images into the requested box, following the fit mode and gravity.
+
+use common::transform::{Fit, Gravity};
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, GrayImage, Rgba, RgbaImage,
+};
+
+const FILTER: FilterType = FilterType::Lanczos3;
+
+/// Crop positions tried along the overflowing axis for smart gravity.
+const SMART_STEPS: u64 = 16;
+
+/// Pixels sampled per crop position when measuring entropy.
+const ENTROPY_SAMPLES: u64 = 65536;
+
+/// Parses a background color given as `rrggbb` or `rrggbbaa` hex, with an optional `#`.
+pub(super) fn parse_color(color: &str) -> Option<Rgba<u8>> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut rgba = [u8::MAX; 4];
+    for (channel, i) in rgba.iter_mut().zip((0..hex.len()).step_by(2)) {
+        *channel = u8::from_str_radix(&hex[i..i + 2], 16).ok()?;
+    }
+    Some(Rgba(rgba))
+}
+
+/// Size of the largest image a resize of a `source` sized image allocates: the output, or
+/// the scaled image before cropping for cover.
+pub(super) fn planned_size(
+    (source_width, source_height): (u32, u32),
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+) -> (u64, u64) {
+    match (width, height) {
+        (None, None) => (source_width.into(), source_height.into()),
+        (Some(width), None) => (
+            width.into(),
+            scaled_side(source_height, width, source_width),
+        ),
+        (None, Some(height)) => (
+            scaled_side(source_width, height, source_height),
+            height.into(),
+        ),
+        (Some(width), Some(height)) if fit == Fit::Cover => {
+            let ratio = f64::max(
+                width as f64 / source_width as f64,
+                height as f64 / source_height as f64,
+            );
+            (
+                ((source_width as f64 * ratio).round() as u64).max(width.into()),
+                ((source_height as f64 * ratio).round() as u64).max(height.into()),
+            )
+        }
+        (Some(width), Some(height)) => (width.into(), height.into()),
+    }
+}
+
+/// `side` scaled by `to / from`, rounded and at least one pixel.
+fn scaled_side(side: u32, to: u32, from: u32) -> u64 {
+    ((side as f64 * to as f64 / from as f64).round() as u64).max(1)
+}
+
+/// Resizes `img`, which must have been checked against the pixel limit with [`planned_size`].
+pub(super) fn resize(
+    img: DynamicImage,
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+    gravity: Gravity,
+    background: Rgba<u8>,
+) -> DynamicImage {
+    let (width, height) = match (width, height) {
+        (None, None) => return img,
+        (Some(width), Some(height)) => (width, height),
+        // The missing dimension follows the aspect ratio, which leaves nothing to crop or pad
+        (width, height) => {
+            let (width, height) = planned_size((img.width(), img.height()), width, height, fit);
+            return img.resize_exact(width as u32, height as u32, FILTER);
+        }
+    };
+
+    match fit {
+        Fit::Contain => img.resize(width, height, FILTER),
+        Fit::Fill => img.resize_exact(width, height, FILTER),
+        Fit::Cover if gravity == Gravity::Center => img.resize_to_fill(width, height, FILTER),
+        Fit::Cover => cover(&img, width, height, gravity),
+        Fit::Pad => pad(&img, width, height, gravity, background),
+    }
+}
+
+fn cover(img: &DynamicImage, width: u32, height: u32, gravity: Gravity) -> DynamicImage {
+    let ratio = f64::max(
+        width as f64 / img.width() as f64,
+        height as f64 / img.height() as f64,
+    );
+    let scaled_width = ((img.width() as f64 * ratio).round() as u32).max(width);
+    let scaled_height = ((img.height() as f64 * ratio).round() as u32).max(height);
+    let scaled = img.resize_exact(scaled_width, scaled_height, FILTER);
+
+    let (x, y) = match gravity {
+        Gravity::Smart => smart_origin(&scaled, width, height),
+        gravity => origin(gravity, scaled_width - width, scaled_height - height),
+    };
+    scaled.crop_imm(x, y, width, height)
+}
+
+fn pad(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    gravity: Gravity,
+    background: Rgba<u8>,
+) -> DynamicImage {
+    let fitted = img.resize(width, height, FILTER);
+    let (x, y) = origin(gravity, width - fitted.width(), height - fitted.height());
+
+    let mut canvas = RgbaImage::from_pixel(width, height, background);
+    imageops::overlay(&mut canvas, &fitted.to_rgba8(), x.into(), y.into());
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Top-left corner of the kept area, given how much room is left over on each axis.
+fn origin(gravity: Gravity, spare_x: u32, spare_y: u32) -> (u32, u32) {
+    match gravity {
+        Gravity::Center | Gravity::Smart => (spare_x / 2, spare_y / 2),
+        Gravity::Top => (spare_x / 2, 0),
+        Gravity::Bottom => (spare_x / 2, spare_y),
+        Gravity::Left => (0, spare_y / 2),
+        Gravity::Right => (spare_x, spare_y / 2),
+    }
+}
+
+/// Picks the crop with the most detail, measured as the entropy of its luma histogram.
+/// Ties go to the centered crop.
+fn smart_origin(img: &DynamicImage, width: u32, height: u32) -> (u32, u32) {
+    let luma = img.to_luma8();
+    let (spare_x, spare_y) = (img.width() - width, img.height() - height);
+    let positions = |spare: u32| {
+        let mut positions = (0..=SMART_STEPS)
+            .map(|i| (spare as u64 * i / SMART_STEPS) as u32)
+            .collect::<Vec<_>>();
+        positions.dedup();
+        positions
+    };
+
+    let mut best = origin(Gravity::Center, spare_x, spare_y);
+    let mut best_entropy = entropy(&luma, best, width, height);
+    for x in positions(spare_x) {
+        for y in positions(spare_y) {
+            let entropy = entropy(&luma, (x, y), width, height);
+            if entropy > best_entropy {
+                best = (x, y);
+                best_entropy = entropy;
+            }
+        }
+    }
+    best
+}
+
+fn entropy(luma: &GrayImage, (x, y): (u32, u32), width: u32, height: u32) -> f64 {
+    // Sample a grid rather than every pixel, large crops would be slow otherwise
+    let area = width as u64 * height as u64;
+    let step = ((area / ENTROPY_SAMPLES) as f64).sqrt().max(1.0) as usize;
+
+    let mut histogram = [0u64; 256];
+    for py in (y..y + height).step_by(step) {
+        for px in (x..x + width).step_by(step) {
+            histogram[luma.get_pixel(px, py).0[0] as usize] += 1;
+        }
+    }
+
+    let total = histogram.iter().sum::<u64>() as f64;
+    histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
@@ -0,0 +1,122 @@
+//! Server-side validation of uploaded images.
+//!
+//! The client's multipart content type and file name are never trusted, the
+//! format is detected from the file's magic bytes instead.
+
+use axum::http::StatusCode;
+use common::config::LimitsConfig;
+use image::{ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+
+/// Formats that are accepted for upload.
+const ALLOWED_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::WebP,
+    ImageFormat::Gif,
+    ImageFormat::Bmp,
+    ImageFormat::Tiff,
+];
+
+#[derive(Debug)]
+pub enum ValidationError {
+    NotAnImage,
+    UnsupportedFormat(ImageFormat),
+    Undecodable(String),
+    TooLarge { width: u32, height: u32 },
+    FileTooLarge,
+}
+
+impl From<ValidationError> for StatusCode {
+    fn from(error: ValidationError) -> StatusCode {
+        match error {
+            ValidationError::NotAnImage => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ValidationError::UnsupportedFormat(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ValidationError::Undecodable(_) => StatusCode::BAD_REQUEST,
+            ValidationError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ValidationError::FileTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::NotAnImage => write!(f, "File is not a recognized image"),
+            ValidationError::UnsupportedFormat(format) => {
+                write!(f, "Unsupported image format: {:?}", format)
+            }
+            ValidationError::Undecodable(err) => write!(f, "Failed to read image: {}", err),
+            ValidationError::TooLarge { width, height } => {
+                write!(f, "Image dimensions {}x{} exceed the limit", width, height)
+            }
+            ValidationError::FileTooLarge => write!(f, "File exceeds the size limit"),
+        }
+    }
+}
+
+/// The real format and dimensions of an uploaded image.
+#[derive(Debug, Clone, Copy)]
+pub struct SniffedImage {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl SniffedImage {
+    /// Canonical file extension for the detected format.
+    pub fn extension(&self) -> &'static str {
+        self.format
+            .extensions_str()
+            .first()
+            .copied()
+            .unwrap_or("bin")
+    }
+
+    /// Canonical MIME type for the detected format.
+    pub fn mime_type(&self) -> &'static str {
+        self.format.to_mime_type()
+    }
+}
+
+/// Decoder limits applied whenever an image is decoded.
+pub fn decode_limits(config: &LimitsConfig) -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(config.max_width);
+    limits.max_image_height = Some(config.max_height);
+    limits
+}
+
+/// Detects the format of `data` from its magic bytes and reads its dimensions from the header,
+/// without decoding the pixel data.
+pub fn sniff_image(data: &[u8], config: &LimitsConfig) -> Result<SniffedImage, ValidationError> {
+    let format = image::guess_format(data).map_err(|_| ValidationError::NotAnImage)?;
+    if !ALLOWED_FORMATS.contains(&format) {
+        return Err(ValidationError::UnsupportedFormat(format));
+    }
+
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(decode_limits(config));
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|e| ValidationError::Undecodable(e.to_string()))?;
+
+    if width == 0 || height == 0 {
+        return Err(ValidationError::Undecodable(
+            "Image has no pixels".to_string(),
+        ));
+    }
+
+    if width > config.max_width
+        || height > config.max_height
+        || u64::from(width) * u64::from(height) > config.max_pixels
+    {
+        return Err(ValidationError::TooLarge { width, height });
+    }
+
+    Ok(SniffedImage {
+        format,
+        width,
+        height,
+    })
+}
@@ -0,0 +1,176 @@
+//! Rendered variants kept in storage under `variants/<file_id>/`, a second cache tier behind
+//! Redis. Variants there survive evictions and restarts, so an image is only decoded again
+//! once its variant has been stored for longer than the configured TTL.
+
+use crate::cache::CachedImage;
+use crate::storage::{Storage, StorageError};
+use bytes::{Buf, BufMut, Bytes};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+const PREFIX: &str = "variants/";
+
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Key prefix of all variants of an image.
+fn prefix(file_id: &str) -> String {
+    format!("{}{}/", PREFIX, file_id)
+}
+
+/// Cache keys contain characters object keys shouldn't, so variants are stored by digest.
+fn key(file_id: &str, cache_key: &str) -> String {
+    let digest = Sha256::digest(cache_key);
+    format!("{}{}", prefix(file_id), hex::encode(&digest[..16]))
+}
+
+/// Prefixes a cache entry with the Unix timestamp it's stored at, big-endian.
+fn encode(image: &CachedImage, stored_at: i64) -> Vec<u8> {
+    let entry = image.encode();
+    let mut object = Vec::with_capacity(8 + entry.len());
+    object.put_i64(stored_at);
+    object.put_slice(&entry);
+    object
+}
+
+fn decode(mut object: Bytes) -> Option<(CachedImage, i64)> {
+    if object.len() < 8 {
+        return None;
+    }
+    let stored_at = object.get_i64();
+    Some((CachedImage::decode(object)?, stored_at))
+}
+
+/// Fetches a stored variant, unless it's older than `ttl_secs`.
+pub async fn get(
+    storage: &dyn Storage,
+    file_id: &str,
+    cache_key: &str,
+    ttl_secs: u64,
+) -> Result<Option<CachedImage>, StorageError> {
+    let object = match storage.get(&key(file_id, cache_key)).await {
+        Ok(object) => object,
+        Err(StorageError::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    match decode(object) {
+        Some((image, stored_at)) if Utc::now().timestamp() - stored_at <= ttl_secs as i64 => {
+            Ok(Some(image))
+        }
+        Some(_) => {
+            debug!("Ignoring expired variant: {}", cache_key);
+            Ok(None)
+        }
+        None => {
+            debug!("Ignoring unreadable variant: {}", cache_key);
+            Ok(None)
+        }
+    }
+}
+
+/// Stores a variant, replacing an expired one.
+pub async fn put(
+    storage: &dyn Storage,
+    file_id: &str,
+    cache_key: &str,
+    image: &CachedImage,
+) -> Result<(), StorageError> {
+    debug!("Storing variant: {}", cache_key);
+    let object = encode(image, Utc::now().timestamp());
+    storage
+        .put(&key(file_id, cache_key), &object, CONTENT_TYPE)
+        .await
+}
+
+async fn delete_prefix(storage: &dyn Storage, prefix: &str) -> Result<u64, StorageError> {
+    let objects = storage.list(prefix).await?;
+    for object in &objects {
+        storage.delete(&object.key).await?;
+    }
+    Ok(objects.len() as u64)
+}
+
+/// Deletes all stored variants of an image, returns how many there were.
+pub async fn purge(storage: &dyn Storage, file_id: &str) -> Result<u64, StorageError> {
+    delete_prefix(storage, &prefix(file_id)).await
+}
+
+/// Deletes the stored variants of every image, returns how many there were.
+pub async fn purge_all(storage: &dyn Storage) -> Result<u64, StorageError> {
+    delete_prefix(storage, PREFIX).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use chrono::DateTime;
+
+    fn image() -> CachedImage {
+        CachedImage {
+            content_type: "image/webp".to_string(),
+            etag: "\"0123456789abcdef\"".to_string(),
+            last_modified: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            data: Bytes::from_static(b"RIFF\0\0\0\0WEBPVP8 "),
+        }
+    }
+
+    #[tokio::test]
+    async fn stored_variants_are_found() {
+        let storage = MemoryStorage::default();
+        put(&storage, "file", "img:file:a", &image()).await.unwrap();
+
+        assert_eq!(
+            get(&storage, "file", "img:file:a", 60).await.unwrap(),
+            Some(image())
+        );
+        assert_eq!(get(&storage, "file", "img:file:b", 60).await.unwrap(), None);
+        assert_eq!(
+            get(&storage, "other", "img:file:a", 60).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_variants_are_misses() {
+        let storage = MemoryStorage::default();
+        let stored_at = Utc::now().timestamp() - 120;
+        let object = encode(&image(), stored_at);
+        storage
+            .put(&key("file", "img:file:a"), &object, CONTENT_TYPE)
+            .await
+            .unwrap();
+
+        assert_eq!(get(&storage, "file", "img:file:a", 60).await.unwrap(), None);
+        assert_eq!(
+            get(&storage, "file", "img:file:a", 600).await.unwrap(),
+            Some(image())
+        );
+    }
+
+    #[tokio::test]
+    async fn purging_only_deletes_variants() {
+        let storage = MemoryStorage::default();
+        put(&storage, "file", "img:file:a", &image()).await.unwrap();
+        put(&storage, "file", "img:file:b", &image()).await.unwrap();
+        put(&storage, "other", "img:other:a", &image())
+            .await
+            .unwrap();
+        storage
+            .put("file.png", b"original", "image/png")
+            .await
+            .unwrap();
+
+        assert_eq!(purge(&storage, "file").await.unwrap(), 2);
+        assert_eq!(get(&storage, "file", "img:file:a", 60).await.unwrap(), None);
+        assert!(get(&storage, "other", "img:other:a", 60)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(storage.get("file.png").await.is_ok());
+
+        assert_eq!(purge_all(&storage).await.unwrap(), 1);
+        assert!(storage.get("file.png").await.is_ok());
+    }
+}
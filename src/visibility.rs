@@ -0,0 +1,24 @@
+//! Conversions between the API's and the database's image visibility.
+
+use crate::db;
+use common::upload::Visibility;
+
+impl From<Visibility> for db::Visibility {
+    fn from(visibility: Visibility) -> db::Visibility {
+        match visibility {
+            Visibility::Public => db::Visibility::Public,
+            Visibility::Unlisted => db::Visibility::Unlisted,
+            Visibility::Private => db::Visibility::Private,
+        }
+    }
+}
+
+impl From<db::Visibility> for Visibility {
+    fn from(visibility: db::Visibility) -> Visibility {
+        match visibility {
+            db::Visibility::Public => Visibility::Public,
+            db::Visibility::Unlisted => Visibility::Unlisted,
+            db::Visibility::Private => Visibility::Private,
+        }
+    }
+}